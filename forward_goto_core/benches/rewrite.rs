@@ -0,0 +1,105 @@
+//! Measures the rewriting time of synthetic functions with thousands of statements.
+//!
+//! Run with `cargo bench -p forward_goto_core`. The time per statement
+//! should stay roughly the same when the functions grow.
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use std::time::{Duration, Instant};
+use syn::{ItemFn, Lifetime};
+
+fn label(i: usize) -> Lifetime {
+    Lifetime::new(&format!("'l{}", i), Span::call_site())
+}
+
+/// Short jumps over a few statements each, like the states of a generated parser.
+fn sequential(stmts: usize) -> TokenStream {
+    let body = (0..stmts / 10).map(|i| {
+        let label = label(i);
+        quote! {
+            if c(#i) {
+                forward_goto!(#label);
+            }
+            let x = v(#i);
+            s(x);
+            s(#i);
+            s(#i);
+            s(#i);
+            s(#i);
+            s(#i);
+            s(#i);
+            forward_label!(#label);
+        }
+    });
+    quote!(#(#body)*)
+}
+
+/// Many gotos at the start of the function to labels spread across it.
+fn overlapping(stmts: usize) -> TokenStream {
+    let labels = stmts / 50;
+    let gotos = (0..labels).map(|i| {
+        let label = label(i);
+        quote!(if c(#i) { forward_goto!(#label); })
+    });
+    let body = (0..labels).map(|i| {
+        let label = label(i);
+        let filler = (0..48).map(|j| quote!(s(#j);));
+        quote! {
+            #(#filler)*
+            forward_label!(#label);
+        }
+    });
+    quote!(#(#gotos)* #(#body)*)
+}
+
+/// Labels inside of nested blocks, so the statements after them are lifted out.
+fn nested(stmts: usize) -> TokenStream {
+    let body = (0..stmts / 10).map(|i| {
+        let label = label(i);
+        quote! {
+            if c(#i) {
+                forward_goto!(#label);
+            }
+            s(#i);
+            if c(#i) {
+                s(#i);
+                {
+                    s(#i);
+                    forward_label!(#label);
+                    s(#i);
+                    s(#i);
+                }
+                s(#i);
+            }
+        }
+    });
+    quote!(#(#body)*)
+}
+
+fn measure(name: &str, shape: fn(usize) -> TokenStream) {
+    for &stmts in &[2_500, 5_000, 10_000] {
+        let body = shape(stmts);
+        let item: ItemFn = syn::parse2(quote!(fn generated() { #body })).unwrap();
+
+        let runs = 5;
+        let mut total = Duration::default();
+        for _ in 0..runs {
+            let mut item = item.clone();
+            let start = Instant::now();
+            forward_goto_core::rewrite_fn(&mut item).unwrap();
+            total += start.elapsed();
+        }
+
+        let per_run = total / runs;
+        println!(
+            "{:<12} {:>6} statements: {:>9.2?} ({:.2?} per statement)",
+            name, stmts, per_run, per_run / stmts as u32
+        );
+    }
+}
+
+fn main() {
+    measure("sequential", sequential);
+    measure("overlapping", overlapping);
+    measure("nested", nested);
+}
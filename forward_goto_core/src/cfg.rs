@@ -0,0 +1,112 @@
+use syn::*;
+use syn::visit::{self, Visit};
+use super::result::ErrInfo;
+
+/// Reports labels that are behind `#[cfg]`.
+///
+/// The loops that are generated for a label enclose the statements in front of it,
+/// no matter whether the label is compiled or not. Gotos keep their attributes instead,
+/// so a goto that is compiled out simply never jumps.
+pub fn check(block: &Block) -> std::result::Result<(), Vec<ErrInfo>> {
+    let mut check = Check { in_cfg: false, errors: Vec::new() };
+    check.visit_block(block);
+
+    if check.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(check.errors)
+    }
+}
+
+struct Check {
+    in_cfg: bool,
+    errors: Vec<ErrInfo>,
+}
+
+impl Check {
+    fn with_attrs(&mut self, attrs: &[Attribute], f: impl FnOnce(&mut Self)) {
+        let in_cfg = self.in_cfg;
+        self.in_cfg |= attrs.iter().any(|attr| attr.path.is_ident("cfg"));
+        f(self);
+        self.in_cfg = in_cfg;
+    }
+}
+
+impl<'ast> Visit<'ast> for Check {
+    fn visit_item(&mut self, _item: &'ast Item) {}
+
+    fn visit_stmt(&mut self, stmt: &'ast Stmt) {
+        let attrs = match stmt {
+            Stmt::Local(local) => &local.attrs[..],
+            Stmt::Expr(expr) | Stmt::Semi(expr, _) => expr_attrs(expr),
+            Stmt::Item(_) => &[],
+        };
+        self.with_attrs(attrs, |this| visit::visit_stmt(this, stmt));
+    }
+
+    fn visit_arm(&mut self, arm: &'ast Arm) {
+        self.with_attrs(&arm.attrs, |this| visit::visit_arm(this, arm));
+    }
+
+    fn visit_field_value(&mut self, field: &'ast FieldValue) {
+        self.with_attrs(&field.attrs, |this| visit::visit_field_value(this, field));
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        if self.in_cfg && mac.path.is_ident("forward_label") {
+            if let Ok(label) = mac.parse_body::<Lifetime>() {
+                self.errors.push((label.span(), format!(
+                    "Label {} cannot be behind #[cfg], because the rewritten control flow cannot depend on the configuration. \
+                    Put the #[cfg] on the statements next to the label instead",
+                    label
+                )));
+            }
+        }
+    }
+}
+
+/// Returns the outer attributes of `expr`, which includes the attributes of the statement it forms.
+fn expr_attrs(expr: &Expr) -> &[Attribute] {
+    match expr {
+        Expr::Array(e) => &e.attrs,
+        Expr::Assign(e) => &e.attrs,
+        Expr::AssignOp(e) => &e.attrs,
+        Expr::Async(e) => &e.attrs,
+        Expr::Await(e) => &e.attrs,
+        Expr::Binary(e) => &e.attrs,
+        Expr::Block(e) => &e.attrs,
+        Expr::Box(e) => &e.attrs,
+        Expr::Break(e) => &e.attrs,
+        Expr::Call(e) => &e.attrs,
+        Expr::Cast(e) => &e.attrs,
+        Expr::Closure(e) => &e.attrs,
+        Expr::Continue(e) => &e.attrs,
+        Expr::Field(e) => &e.attrs,
+        Expr::ForLoop(e) => &e.attrs,
+        Expr::Group(e) => &e.attrs,
+        Expr::If(e) => &e.attrs,
+        Expr::Index(e) => &e.attrs,
+        Expr::Let(e) => &e.attrs,
+        Expr::Lit(e) => &e.attrs,
+        Expr::Loop(e) => &e.attrs,
+        Expr::Macro(e) => &e.attrs,
+        Expr::Match(e) => &e.attrs,
+        Expr::MethodCall(e) => &e.attrs,
+        Expr::Paren(e) => &e.attrs,
+        Expr::Path(e) => &e.attrs,
+        Expr::Range(e) => &e.attrs,
+        Expr::Reference(e) => &e.attrs,
+        Expr::Repeat(e) => &e.attrs,
+        Expr::Return(e) => &e.attrs,
+        Expr::Struct(e) => &e.attrs,
+        Expr::Try(e) => &e.attrs,
+        Expr::TryBlock(e) => &e.attrs,
+        Expr::Tuple(e) => &e.attrs,
+        Expr::Type(e) => &e.attrs,
+        Expr::Unary(e) => &e.attrs,
+        Expr::Unsafe(e) => &e.attrs,
+        Expr::While(e) => &e.attrs,
+        Expr::Yield(e) => &e.attrs,
+        _ => &[],
+    }
+}
@@ -0,0 +1,206 @@
+use syn::*;
+use syn::spanned::Spanned;
+use syn::visit::Visit;
+use syn::visit_mut::{self, VisitMut};
+use proc_macro2::Span;
+use super::forms;
+use super::result::ErrInfo;
+
+const SLOT: &str = "__forward_goto_return";
+
+/// Routes every `return` and `?` in front of the label `cleanup` through it.
+///
+/// The value is stored in a variable that is declared at the start of the function
+/// and returned at its end, after the statements behind the label:
+///
+/// ```text
+/// return value;
+///
+/// { __forward_goto_return = Some(value); forward_goto!('cleanup) };
+///
+/// expr?
+///
+/// match expr {
+///     Ok(value) => value,
+///     Err(err) => { __forward_goto_return = Some(Err(From::from(err))); forward_goto!('cleanup) },
+/// }
+/// ```
+///
+/// In functions that return an `Option`, `?` stores `None` instead. The form is taken from the name
+/// of the return type, so `?` is an error for other return types.
+/// Returns behind the label, in closures, in async blocks and in items are not changed.
+pub fn lower(body: &mut Block, output: &ReturnType, cleanup: &Lifetime) -> std::result::Result<(), Vec<ErrInfo>> {
+    let cleanup_label = |stmt: &Stmt| match stmt {
+        Stmt::Semi(Expr::Macro(mac), _) | Stmt::Expr(Expr::Macro(mac)) if mac.mac.path.is_ident("forward_label") => {
+            parse2::<Lifetime>(mac.mac.tokens.clone()).ok().filter(|label| label.ident == cleanup.ident)
+        },
+        _ => None,
+    };
+
+    // the gotos use the label of the body, which might come from a different macro expansion than the option
+    let (position, cleanup) = match body.stmts.iter().enumerate().find_map(|(i, stmt)| Some((i, cleanup_label(stmt)?))) {
+        Some(found) => found,
+        None => {
+            let span = cleanup.span();
+            return Err(vec![(span, format!(
+                "The cleanup option needs the statement `forward_label!({});` in the body of the function",
+                cleanup
+            ))]);
+        },
+    };
+    let cleanup = &cleanup;
+
+    let output = match output {
+        ReturnType::Default => None,
+        ReturnType::Type(_, ty) if matches!(&**ty, Type::Tuple(tuple) if tuple.elems.is_empty()) => None,
+        ReturnType::Type(_, ty) => Some(&**ty),
+    };
+
+    // only unit functions have a return value for the statements that fall through to the label
+    let falls_through = position == 0 || !forms::diverges(&body.stmts[position - 1]);
+    if output.is_some() && falls_through {
+        return Err(vec![(cleanup.span(), format!(
+            "The statements in front of the cleanup label {} reach it without a return value. \
+            End them with `return`, or jump to the label",
+            cleanup
+        ))]);
+    }
+
+    // the cleanup section ends by returning the stored value, so it cannot have a value of its own
+    if let Some(Stmt::Expr(tail)) = body.stmts.last().filter(|stmt| cleanup_label(stmt).is_none()) {
+        return Err(vec![(tail.span(), format!(
+            "The statements behind the cleanup label {} cannot end with an expression, \
+            because the function returns the value that reached the label. End it with `;`",
+            cleanup
+        ))]);
+    }
+
+    // `?` can only be rewritten if the return type shows which form it has
+    let try_form = match output {
+        Some(Type::Path(TypePath { qself: None, path })) => match path.segments.last() {
+            Some(segment) if segment.ident == "Option" => Some(TryForm::Option),
+            Some(segment) if segment.ident == "Result" => Some(TryForm::Result),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let span = cleanup.span();
+    let slot = Ident::new(SLOT, Span::mixed_site());
+    let mut lowering = Lowering { slot: &slot, cleanup, try_form, errors: Vec::new() };
+    for stmt in &mut body.stmts[..position] {
+        lowering.visit_stmt_mut(stmt);
+    }
+    if !lowering.errors.is_empty() {
+        return Err(lowering.errors);
+    }
+    let message = format!("{} was reached without a return value", cleanup);
+    body.stmts.push(parse_quote_spanned!(span=> #[allow(unreachable_code, clippy::needless_return)] return #slot.expect(#message);));
+
+    // the type of the slot cannot be written down if the return type contains `impl Trait`
+    let decl = match output {
+        None => parse_quote_spanned!(span=> let mut #slot: ::core::option::Option<()> = ::core::option::Option::Some(());),
+        Some(ty) if contains_impl_trait(ty) => parse_quote_spanned!(span=> let mut #slot = ::core::option::Option::None;),
+        Some(ty) => parse_quote_spanned!(span=> let mut #slot: ::core::option::Option<#ty> = ::core::option::Option::None;),
+    };
+    body.stmts.insert(0, decl);
+
+    Ok(())
+}
+
+fn contains_impl_trait(ty: &Type) -> bool {
+    struct Finder(bool);
+
+    impl<'ast> Visit<'ast> for Finder {
+        fn visit_type_impl_trait(&mut self, _impl_trait: &'ast TypeImplTrait) {
+            self.0 = true;
+        }
+    }
+
+    let mut finder = Finder(false);
+    finder.visit_type(ty);
+    finder.0
+}
+
+/// The kind of value that `?` returns early.
+#[derive(Clone, Copy)]
+enum TryForm {
+    Option,
+    Result,
+}
+
+struct Lowering<'l> {
+    slot: &'l Ident,
+    cleanup: &'l Lifetime,
+    /// `None` if the return type is neither an `Option` nor a `Result`.
+    try_form: Option<TryForm>,
+    errors: Vec<ErrInfo>,
+}
+
+impl<'l> Lowering<'l> {
+    /// Returns a block that stores `value` and jumps to the cleanup label.
+    fn store(&self, value: Expr, span: Span) -> Expr {
+        let Self { slot, cleanup, .. } = self;
+        parse_quote_spanned!(span=> {
+            #slot = ::core::option::Option::Some(#value);
+            forward_goto!(#cleanup)
+        })
+    }
+}
+
+impl<'l> VisitMut for Lowering<'l> {
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+    fn visit_expr_closure_mut(&mut self, _closure: &mut ExprClosure) {}
+
+    fn visit_expr_async_mut(&mut self, _async_block: &mut ExprAsync) {}
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        visit_mut::visit_expr_mut(self, expr);
+
+        let span = expr.span();
+        match expr {
+            Expr::Return(ExprReturn { attrs, expr: value, .. }) => {
+                let value = value.take().map_or_else(|| parse_quote_spanned!(span=> ()), |value| *value);
+                let mut store = self.store(value, span);
+                if let Expr::Block(ExprBlock { attrs: store_attrs, .. }) = &mut store {
+                    *store_attrs = std::mem::take(attrs);
+                }
+                *expr = store;
+            },
+            Expr::Try(ExprTry { attrs, expr: inner, question_token }) => {
+                let span = question_token.span();
+                let inner = &**inner;
+                let try_form = match self.try_form {
+                    Some(try_form) => try_form,
+                    None => {
+                        self.errors.push((span, format!(
+                            "`?` in front of the cleanup label {} needs a function that returns `Option` or `Result`, \
+                            written with that name",
+                            self.cleanup
+                        )));
+                        return;
+                    },
+                };
+                *expr = if let TryForm::Option = try_form {
+                    let store = self.store(parse_quote_spanned!(span=> ::core::option::Option::None), span);
+                    parse_quote_spanned!(span=> #(#attrs)* match #inner {
+                        ::core::option::Option::Some(value) => value,
+                        ::core::option::Option::None => #store,
+                    })
+                } else {
+                    let store = self.store(parse_quote_spanned!(span=> ::core::result::Result::Err(err)), span);
+                    parse_quote_spanned!(span=> #(#attrs)* match #inner {
+                        ::core::result::Result::Ok(value) => value,
+                        ::core::result::Result::Err(err) => {
+                            #[allow(clippy::useless_conversion)]
+                            let err = ::core::convert::From::from(err);
+                            #store
+                        },
+                    })
+                };
+            },
+            _ => (),
+        }
+    }
+}
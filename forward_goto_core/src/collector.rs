@@ -0,0 +1,551 @@
+// the collector predates these lints
+#![allow(clippy::legacy_numeric_constants, clippy::mem_replace_with_default, clippy::unused_unit, clippy::single_match)]
+
+use syn::*;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use indexmap::{IndexMap, IndexSet};
+use fix_fn::fix_fn;
+use super::result::{ErrInfo, Result, err};
+use super::labels::{self, LabelNames};
+use super::report::Report;
+use super::scope::Hoisting;
+use super::coverage;
+use super::trace;
+use syn::spanned::Spanned;
+
+pub type Level = u32;
+
+type ContinuationMap = IndexMap<Lifetime, (Vec<Stmt>, Vec<Lifetime>)>;
+pub type Continuation = (Vec<Lifetime>, Vec<Stmt>, Lifetime);
+
+/// The statements that have to be wrapped into a loop,
+/// because all gotos to the current labels have been found.
+pub struct Wrapper {
+    /// Index of the first statement that is wrapped.
+    pub start_index: usize,
+    /// Label of the outermost loop.
+    pub end_label: Lifetime,
+    /// The labels that are resolved by this wrapper.
+    pub labels: Vec<Lifetime>,
+    pub continuations: Vec<Continuation>,
+}
+
+/// Gotos and hoisted declarations that are currently attributed
+/// to the statement that is being traversed at some level.
+#[derive(Default)]
+struct Frame {
+    gotos: Vec<Lifetime>,
+    hoisted: Vec<(usize, Stmt)>,
+}
+
+pub struct Collector {
+    names: LabelNames,
+    hoisting: Hoisting,
+    loop_labels: Vec<Lifetime>,
+    level: Level,
+    continuation_level: Level,
+    index: usize,
+    gotos: IndexMap<Lifetime, (Level, usize)>,
+    labels: IndexSet<Lifetime>,
+    continuations: ContinuationMap,
+    prev_conts: Vec<Lifetime>,
+    frames: Vec<Frame>,
+    traversed: HashSet<Lifetime>,
+    report: Report,
+    /// The path of the `forward_goto` crate and the name of the function, if its gotos are reported at runtime.
+    trace: Option<(Path, Ident)>,
+    /// The counted gotos, if the coverage of the function is measured.
+    coverage: Option<coverage::Edges>,
+    errors: Vec<(ErrInfo, u32)>,
+}
+
+impl Collector {
+    pub fn new(names: LabelNames, hoisting: Hoisting, report: Report) -> Self {
+        Self {
+            names,
+            hoisting,
+            loop_labels: Vec::new(),
+            level: 0,
+            continuation_level: 0,
+            gotos: IndexMap::new(),
+            labels: IndexSet::new(),
+            index: usize::max_value(),
+            continuations: IndexMap::new(),
+            prev_conts: Vec::new(),
+            frames: Vec::new(),
+            traversed: HashSet::new(),
+            report,
+            trace: None,
+            coverage: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Records that `wrapper` encloses the statements `wrapped`.
+    pub fn report_wrap(&mut self, wrapper: &Wrapper, wrapped: &[Stmt]) {
+        self.report.add_wrap(&wrapper.labels, &wrapper.end_label, wrapped, &wrapper.continuations);
+    }
+
+    pub fn take_report(&mut self) -> Report {
+        std::mem::take(&mut self.report)
+    }
+
+    /// Takes the names of all generated loops and labels.
+    pub fn take_generated_labels(&mut self) -> HashSet<Ident> {
+        self.names.take_generated()
+    }
+
+    /// Marks the generated loop `label` as containing only statements that were already traversed.
+    pub fn mark_traversed(&mut self, label: Lifetime) {
+        self.traversed.insert(label);
+    }
+
+    /// Returns whether the generated loop `label` was marked by [`Collector::mark_traversed`].
+    pub fn take_traversed(&mut self, label: &Lifetime) -> bool {
+        !self.traversed.is_empty() && self.traversed.remove(label)
+    }
+
+    fn frame(&mut self, level: Level) -> &mut Frame {
+        let level = level as usize;
+        if self.frames.len() <= level {
+            self.frames.resize_with(level + 1, Frame::default);
+        }
+        &mut self.frames[level]
+    }
+
+    fn new_lifetime(&mut self, span: proc_macro2::Span) -> Lifetime {
+        self.names.new_continuation(span)
+    }
+
+    /// Creates the label at the start of an arm that a `fallthrough!()` continues into.
+    pub fn fallthrough_label(&mut self, span: proc_macro2::Span) -> Lifetime {
+        self.names.new_fallthrough(span)
+    }
+
+    /// Reports the gotos of the function `function` at runtime to the crate `krate`.
+    pub fn trace(&mut self, krate: Path, function: Ident) {
+        self.trace = Some((krate, function));
+    }
+
+    /// Returns the call that reports the goto to `label` at runtime, if the gotos are traced.
+    ///
+    /// Generated gotos, like the ones of `fallthrough!()`, are not reported.
+    pub fn trace_goto(&self, label: &Lifetime, span: proc_macro2::Span) -> Option<Expr> {
+        match &self.trace {
+            Some((krate, function)) if !self.names.is_internal(label) => Some(trace::hit(krate, function, trace::Event::Goto, label, span)),
+            _ => None,
+        }
+    }
+
+    /// Counts how often the gotos of the function are taken in a table of the crate `krate`.
+    pub fn count_gotos(&mut self, krate: Path) {
+        self.coverage = Some(coverage::Edges::new(krate));
+    }
+
+    /// Returns the call that counts the goto to `label`, if the gotos are counted.
+    ///
+    /// Generated gotos, like the ones of `fallthrough!()`, are not counted.
+    pub fn count_goto(&mut self, label: &Lifetime, span: proc_macro2::Span) -> Option<Expr> {
+        match &mut self.coverage {
+            Some(edges) if !self.names.is_internal(label) => Some(edges.add(label, span)),
+            _ => None,
+        }
+    }
+
+    /// Takes the counted gotos.
+    pub fn take_coverage(&mut self) -> Option<coverage::Edges> {
+        self.coverage.take()
+    }
+
+    /// Returns the label that is generated for the goto label `label`.
+    pub fn user_label(&mut self, label: &Lifetime) -> Result<Lifetime> {
+        if let Some(loop_label) = self.loop_labels.iter().find(|l| l.ident == label.ident) {
+            return Err((label.span(), format!(
+                "Goto label {} has the same name as the enclosing loop label {}. Consider renaming one of them.",
+                label, loop_label
+            )));
+        }
+
+        Ok(self.names.user_label(label))
+    }
+
+    /// Executes `f` with `label` being an enclosing loop label of the user.
+    pub fn with_loop_label<T>(&mut self, label: Option<&Label>, f: impl FnOnce(&mut Self) -> T) -> T {
+        match label {
+            Some(label) => {
+                self.loop_labels.push(label.name.clone());
+                let result = f(self);
+                self.loop_labels.pop();
+                result
+            },
+            None => f(self),
+        }
+    }
+
+    pub fn add_goto(&mut self, label: Lifetime, span: proc_macro2::Span) {
+        assert!(self.index < usize::max_value());
+        self.report.add_goto(&label, span);
+        if !self.gotos.contains_key(&label) {
+            self.gotos.insert(label.clone(), (self.level, self.index));
+            self.frame(self.level).gotos.push(label);
+        }
+    }
+
+    /// Adds a goto for every label that is an argument of another macro,
+    /// e.g. `check!(x > 0, 'fail)`, and renames them to the generated labels.
+    pub fn add_macro_gotos(&mut self, tokens: &mut proc_macro2::TokenStream, span: proc_macro2::Span) -> Result<()> {
+        let labels = labels::macro_labels(tokens, self.names.labels());
+        if labels.is_empty() {
+            return Ok(());
+        }
+
+        let mut renamed = HashMap::new();
+        for label in labels {
+            let generated = self.user_label(&label)?;
+            self.add_goto(generated.clone(), span);
+            renamed.insert(label.ident.to_string(), generated);
+        }
+        *tokens = labels::rename_macro_labels(tokens.clone(), &renamed);
+        Ok(())
+    }
+
+    pub fn add_label(&mut self, label: Lifetime, span: proc_macro2::Span) -> Result<()> {
+        if !self.gotos.contains_key(&label) {
+            return err(label, "Found no goto to this label!")
+        }
+        if self.labels.contains(&label) {
+            return err(label, "Label already used")
+        }
+
+        debug_assert!(self.prev_conts.is_empty());
+        self.report.add_label(&label, span);
+        self.labels.insert(label.clone());
+        self.prev_conts.push(label);
+        self.continuation_level = self.level;
+
+        Ok(())
+    }
+
+    pub fn cut(&mut self) -> CollectorCut<'_> {
+        let labels = std::mem::replace(&mut self.labels, IndexSet::new());
+        let prev_conts = std::mem::replace(&mut self.prev_conts, Vec::new());
+        let continuations = std::mem::replace(&mut self.continuations, IndexMap::new());
+        CollectorCut {
+            collector: self,
+            labels,
+            prev_conts,
+            continuations,
+        }
+    }
+
+    pub fn enter(&mut self) -> CollectorEnter<'_> {
+        self.enter_statement(self.index)
+    }
+
+    pub fn enter_statement(&mut self, index: usize) -> CollectorEnter<'_> {
+        let prev_index = self.index;
+        self.level += 1;
+        self.index = index;
+
+        let prev_conts = std::mem::replace(&mut self.prev_conts, Vec::new());
+        CollectorEnter {
+            collector: self,
+            prev_index,
+            prev_conts,
+        }
+    }
+
+    fn leave_statement(&mut self, prev_index: usize, continuations: Vec<Lifetime>) {
+        assert!(self.level > 0);
+        self.level -= 1;
+        self.continuation_level = min(self.continuation_level, self.level);
+
+        // everything that was attributed to the inner statement is now attributed to the current one
+        let (level, index) = (self.level, self.index);
+        if let Some(inner) = self.frames.get_mut(level as usize + 1) {
+            let gotos = std::mem::take(&mut inner.gotos);
+            let hoisted = std::mem::take(&mut inner.hoisted);
+
+            for label in &gotos {
+                if let Some(p) = self.gotos.get_mut(label) {
+                    *p = (level, index);
+                }
+            }
+
+            let frame = self.frame(level);
+            frame.gotos.extend(gotos);
+            frame.hoisted.extend(hoisted.into_iter().map(|(_, decl)| (index, decl)));
+        }
+
+        self.index = prev_index;
+        self.prev_conts.extend(continuations);
+    }
+
+    /// Returns whether the `let` statement with the id `local` is declared in front of its wrapper.
+    pub fn is_hoisted(&self, local: usize) -> bool {
+        self.hoisting.lets.contains(&local)
+    }
+
+    /// Returns whether the `let` statement with the id `local` has mutable bindings that are moved over a handler.
+    pub fn is_moved_mut(&self, local: usize) -> bool {
+        self.hoisting.moved_mut.contains(&local)
+    }
+
+    /// Registers a declaration that has to be placed in front of
+    /// the wrapper that will enclose the current statement.
+    pub fn add_hoisted(&mut self, decl: Stmt) {
+        assert!(self.index < usize::max_value());
+        let index = self.index;
+        self.frame(self.level).hoisted.push((index, decl));
+    }
+
+    /// Takes the hoisted declarations of all statements that
+    /// will be wrapped, starting with the statement at `start_index`.
+    pub fn take_hoisted(&mut self, start_index: usize) -> Vec<Stmt> {
+        // the declarations of a frame are sorted by their index
+        let frame = self.frame(self.level);
+        let split = frame.hoisted
+            .iter()
+            .rposition(|(index, _)| *index < start_index)
+            .map_or(0, |i| i + 1);
+        let taken = frame.hoisted.split_off(split);
+
+        let mut names = HashSet::new();
+        let mut decls = Vec::new();
+        for (_, decl) in taken {
+            if let Stmt::Local(Local { pat: Pat::Ident(PatIdent { ident, .. }), .. }) = &decl {
+                if !names.insert(ident.clone()) {
+                    self.add_error(ident, format!("Cannot hoist `{}`, because it is declared multiple times in front of the same label", ident));
+                }
+            }
+            decls.push(decl);
+        }
+        decls
+    }
+
+    /// Attributes the open gotos of the wrapper at `index` to the wrapper again,
+    /// after `count` hoisted declarations were placed in front of it.
+    ///
+    /// A wrapper that later encloses these gotos then starts behind the declarations.
+    pub fn skip_hoisted(&mut self, index: usize, count: usize) {
+        let level = self.level;
+        let frame = match self.frames.get(level as usize) {
+            Some(frame) if count > 0 => frame,
+            _ => return,
+        };
+        // only the gotos of the current level can be attributed to the wrapper
+        for label in &frame.gotos {
+            if let Some((goto_level, goto_index)) = self.gotos.get_mut(label) {
+                if *goto_level == level && *goto_index == index {
+                    *goto_index += count;
+                }
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn should_push_continuation(&self) -> bool {
+        !self.labels.is_empty() && self.continuation_level >= self.level
+    }
+
+    pub fn push_continuation(&mut self, continuation: Vec<Stmt>) -> Lifetime {
+        assert!(!self.prev_conts.is_empty());
+        let incoming_label = self.prev_conts[0].clone();
+
+        if continuation.is_empty() && self.prev_conts.len() == 1 {
+            return incoming_label;
+        }
+        
+        // the continuation label inherits the span of the label it originates from
+        let out_label = self.new_lifetime(incoming_label.span());
+
+        let previous_continuations = std::mem::replace(
+            &mut self.prev_conts,
+            vec![out_label.clone()],
+        );
+        self.continuations.insert(out_label, (continuation, previous_continuations));
+        incoming_label
+    }
+
+    #[must_use]
+    pub fn retrieve_continuations(&mut self) -> Option<Wrapper> {
+        let found_gotos_to_all_labels = self.labels
+            .iter()
+            .all(|l| self.gotos.get(l).iter().any(|(lvl, _)| *lvl == self.level));
+
+        if !found_gotos_to_all_labels || self.labels.is_empty() {
+            return None
+        }
+
+
+        let mut smallest_index = usize::MAX;
+        let labels: Vec<_> = self.labels.drain(..).collect();
+
+        for label in &labels {
+            let (_, index) = self.gotos.shift_remove(label).expect("'label' should be in self.goto");
+            smallest_index = min(smallest_index, index);
+            // the name of the user can be reused by the following gotos and labels
+            self.names.retire(label);
+        }
+
+        // the remaining gotos of this level are now inside of the wrapper
+        let mut frame_gotos = std::mem::take(&mut self.frame(self.level).gotos);
+        frame_gotos.retain(|label| match self.gotos.get_mut(label) {
+            Some((_, index)) => {
+                *index = min(*index, smallest_index);
+                true
+            },
+            None => false,
+        });
+        self.frame(self.level).gotos = frame_gotos;
+
+        debug_assert!(!self.prev_conts.is_empty());
+        if self.prev_conts.len() > 1 {
+            self.push_continuation(Vec::new());
+        }
+        debug_assert!(self.prev_conts.len() == 1);
+        let end_label = self.prev_conts.drain(..).next().unwrap();
+        
+        let continuations = &self.continuations;
+        let rec = fix_fn!(
+            |rec, cur: &Lifetime, result: &mut Vec<Lifetime>| -> () {
+                match continuations.get(cur) {
+                    Some((_, prevs)) => {
+                        for p in prevs {
+                             rec(p, result);
+                        }
+
+                        result.push(cur.clone());
+                    },
+                    None => ()
+                }
+            }
+        );
+
+        let sorted_conts_to_generate = {
+            let mut conts_to_generate = Vec::new();
+            rec(&end_label, &mut conts_to_generate);
+            //conts_to_generate.sort_by_key(|e| usize::MAX - e.0);
+            conts_to_generate
+        };
+
+        let mut result = Vec::new();
+
+        for label in sorted_conts_to_generate {
+            let (mut stmts, prevs) = self.continuations.swap_remove(&label).unwrap();
+
+            // remove previous breaks
+            // needed to jump into continuations
+            let breaks_to_remove = stmts.iter().rev().take_while(|s| 
+                matches![s, Stmt::Semi(Expr::Break(ExprBreak { label: Some(_), .. }), _)]
+            ).count();
+            for _ in 0..breaks_to_remove {
+                stmts.pop();
+            }
+
+            result.push((prevs, stmts, label));
+        }
+
+        Some(Wrapper {
+            start_index: smallest_index,
+            end_label,
+            labels,
+            continuations: result,
+        })
+    }
+
+    pub fn check(mut self) -> Result<()> {
+        for (goto, _) in self.gotos.drain(..) {
+            if !self.labels.contains(&goto) {
+                self.errors.push(((goto.span(), "Could not find target label!".into()), 1));
+            }
+        }
+
+        for label in self.labels.drain(..) {
+            self.errors.push(((label.span(), "Found no goto to this label!".into()), 0));
+        }
+
+        let mut errors = std::mem::replace(&mut self.errors, Vec::new());
+        errors.sort_by_key(|(_, p)| *p);
+
+        errors.first().map_or(Ok(()), |(info, _)| Err(info.clone()))
+    }
+
+    pub fn add_error(&mut self, span: impl Spanned, msg: impl Into<String>) {
+        self.errors.push(((span.span(), msg.into()), 5));
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        assert!(self.labels.is_empty());
+        assert!(self.gotos.is_empty());
+        self.continuations.clear();
+        self.prev_conts.clear();
+        self.frames.clear();
+    }
+}
+
+pub struct CollectorEnter<'t> {
+    collector: &'t mut Collector,
+    prev_index: usize,
+    prev_conts: Vec<Lifetime>,
+}
+
+impl<'t> Drop for CollectorEnter<'t> {
+    fn drop(&mut self) {
+        let continuations = std::mem::replace(&mut self.prev_conts, Default::default());
+        Collector::leave_statement(self.collector, self.prev_index, continuations);
+    }
+}
+
+impl <'t> std::ops::Deref for CollectorEnter<'t> {
+    type Target = Collector;
+    fn deref(&self) -> &Collector {
+        self.collector
+    }
+}
+
+impl<'t> std::ops::DerefMut for CollectorEnter<'t> {
+
+    fn deref_mut(&mut self) -> &mut Collector {
+        self.collector
+    }
+}
+
+pub struct CollectorCut<'t> {
+    collector: &'t mut Collector,
+
+    labels: IndexSet<Lifetime>,
+    prev_conts: Vec<Lifetime>,
+    continuations: ContinuationMap,
+}
+
+impl<'t> Drop for CollectorCut<'t> {
+    fn drop(&mut self) {
+        let collector = &mut self.collector;
+
+        for label in collector.labels.drain(..) {
+            collector.errors.push(((label.span(), "Found no goto to this label! Note that gotos cannot jump into expressions that need to provide a result value.".into()), 0));
+        }
+
+        collector.labels = std::mem::replace(&mut self.labels, Default::default());
+        collector.prev_conts = std::mem::replace(&mut self.prev_conts, Default::default());
+        collector.continuations = std::mem::replace(&mut self.continuations, Default::default());
+    }
+}
+
+impl <'t> std::ops::Deref for CollectorCut<'t> {
+    type Target = Collector;
+    fn deref(&self) -> &Collector {
+        self.collector
+    }
+}
+
+impl<'t> std::ops::DerefMut for CollectorCut<'t> {
+
+    fn deref_mut(&mut self) -> &mut Collector {
+        self.collector
+    }
+}
@@ -0,0 +1,61 @@
+use syn::*;
+use proc_macro2::Span;
+
+/// Collects the gotos of a function, which are counted in a static table of `forward_goto::coverage`.
+///
+/// The table is declared at the start of the function, which registers it when it runs:
+///
+/// ```text
+/// static __FORWARD_GOTO_EDGES: [Edge; 1] = [Edge::new("f", "'fail", file!(), line!())];
+/// static __FORWARD_GOTO_COVERAGE: Table = Table::new(&__FORWARD_GOTO_EDGES);
+/// __FORWARD_GOTO_COVERAGE.register();
+///
+/// forward_goto!('fail)
+///
+/// { __FORWARD_GOTO_COVERAGE.hit(0); break 'fail }
+/// ```
+pub struct Edges {
+    /// The path of the `forward_goto` crate.
+    krate: Path,
+    edges: Vec<(String, Span)>,
+}
+
+impl Edges {
+    pub fn new(krate: Path) -> Self {
+        Self { krate, edges: Vec::new() }
+    }
+
+    /// Adds the goto to `label` and returns the call that counts it.
+    ///
+    /// `label` is the label as written by the user, before it was renamed.
+    pub fn add(&mut self, label: &Lifetime, span: Span) -> Expr {
+        let index = self.edges.len();
+        self.edges.push((label.to_string(), span));
+        let table = Ident::new("__FORWARD_GOTO_COVERAGE", Span::call_site());
+        parse_quote_spanned!(span=> #table.hit(#index))
+    }
+
+    /// Declares and registers the table at the start of `block`, if there are any gotos.
+    pub fn declare(self, function: &Ident, block: &mut Block) {
+        if self.edges.is_empty() {
+            return;
+        }
+
+        let krate = &self.krate;
+        let function = function.to_string();
+        let len = self.edges.len();
+        let edges = self.edges.iter().map(|(label, span)| {
+            quote::quote_spanned!(*span=> #krate::coverage::Edge::new(#function, #label, ::core::file!(), ::core::line!()))
+        });
+        let (edges_ident, table) = (
+            Ident::new("__FORWARD_GOTO_EDGES", Span::call_site()),
+            Ident::new("__FORWARD_GOTO_COVERAGE", Span::call_site()),
+        );
+        let decls: Block = parse_quote!({
+            static #edges_ident: [#krate::coverage::Edge; #len] = [#(#edges),*];
+            static #table: #krate::coverage::Table = #krate::coverage::Table::new(&#edges_ident);
+            #table.register();
+        });
+        block.stmts.splice(0..0, decls.stmts);
+    }
+}
@@ -0,0 +1,203 @@
+use syn::*;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use proc_macro2::{Group, Span, TokenStream, TokenTree};
+use quote::quote_spanned;
+use indexmap::IndexMap;
+use std::collections::HashMap;
+use super::result::ErrInfo;
+
+/// The name of the generated enum with a variant for every label taken by address.
+const LABEL_ENUM: &str = "__ForwardGotoLabel";
+
+fn is_label_addr(path: &Path) -> bool {
+    path.is_ident("label_addr") || path.is_ident("label_ref")
+}
+
+/// Lowers computed gotos.
+///
+/// `label_addr!('a)` becomes the variant `a` of a generated enum,
+/// which is declared at the start of the function, and `goto_dyn!(target)` becomes
+/// a `match` on `target` with a `forward_goto!` to the label of every variant.
+///
+/// Every label taken by address has to exist and must come after
+/// every `goto_dyn!` of the function, because all of them are possible targets.
+pub fn lower(block: &mut Block) -> std::result::Result<(), Vec<ErrInfo>> {
+    let mut scan = Scan::default();
+    scan.visit_block(block);
+    if scan.taken.is_empty() && scan.jumps.is_empty() {
+        return Ok(());
+    }
+
+    let mut errors = Vec::new();
+    for (span, message) in &scan.invalid {
+        errors.push((*span, message.clone()));
+    }
+    if scan.taken.is_empty() {
+        for (span, _) in &scan.jumps {
+            errors.push((*span, "goto_dyn! needs at least one label that is taken with label_addr!".into()));
+        }
+    }
+    for (label, span) in &scan.taken {
+        match scan.labels.get(label) {
+            None => errors.push((*span, format!("label_addr! refers to the unknown label {}", label))),
+            Some(position) => {
+                if let Some((jump_span, _)) = scan.jumps.iter().find(|(_, jump)| jump > position) {
+                    errors.push((*jump_span, format!(
+                        "goto_dyn! can only jump forward, but label {} taken by label_addr! comes before it",
+                        label
+                    )));
+                }
+            },
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let labels: Vec<_> = scan.taken.keys().cloned().collect();
+    Lowering { labels: &labels }.visit_block_mut(block);
+
+    let variants = labels.iter().map(|label| &label.ident);
+    let name = Ident::new(LABEL_ENUM, Span::call_site());
+    block.stmts.insert(0, parse_quote! {
+        #[allow(non_camel_case_types, dead_code)]
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        enum #name {
+            #(#variants,)*
+        }
+    });
+
+    Ok(())
+}
+
+/// Collects the labels taken by address, and the positions of labels and computed gotos.
+#[derive(Default)]
+struct Scan {
+    /// Counts the labels and computed gotos in the order they appear.
+    position: usize,
+    taken: IndexMap<Lifetime, Span>,
+    labels: HashMap<Lifetime, usize>,
+    jumps: Vec<(Span, usize)>,
+    invalid: Vec<ErrInfo>,
+}
+
+impl Scan {
+    fn take_label(&mut self, tokens: TokenStream, span: Span) {
+        match parse2::<Lifetime>(tokens) {
+            Ok(label) => {
+                self.taken.entry(label).or_insert(span);
+            },
+            Err(err) => self.invalid.push((span, format!("label_addr! expects a label: {}", err))),
+        }
+    }
+
+    /// Finds `label_addr!` in the tokens of other macros.
+    fn scan_tokens(&mut self, tokens: TokenStream) {
+        let tokens: Vec<_> = tokens.into_iter().collect();
+        for (i, token) in tokens.iter().enumerate() {
+            match token {
+                TokenTree::Ident(ident) if ident == "label_addr" || ident == "label_ref" => {
+                    if let (Some(TokenTree::Punct(bang)), Some(TokenTree::Group(group))) = (tokens.get(i + 1), tokens.get(i + 2)) {
+                        if bang.as_char() == '!' {
+                            self.take_label(group.stream(), ident.span());
+                        }
+                    }
+                },
+                TokenTree::Group(group) => self.scan_tokens(group.stream()),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl<'ast> Visit<'ast> for Scan {
+    fn visit_item(&mut self, _item: &'ast Item) {}
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        if is_label_addr(&mac.path) {
+            self.take_label(mac.tokens.clone(), mac.span());
+        } else if mac.path.is_ident("goto_dyn") {
+            self.position += 1;
+            self.jumps.push((mac.span(), self.position));
+        } else if mac.path.is_ident("forward_label") {
+            self.position += 1;
+            if let Ok(label) = parse2::<Lifetime>(mac.tokens.clone()) {
+                self.labels.entry(label).or_insert(self.position);
+            }
+        } else {
+            self.scan_tokens(mac.tokens.clone());
+        }
+        visit::visit_macro(self, mac);
+    }
+}
+
+/// Replaces `label_addr!` and `goto_dyn!`.
+struct Lowering<'l> {
+    labels: &'l [Lifetime],
+}
+
+impl<'l> Lowering<'l> {
+    fn variant(label: &Lifetime, span: Span) -> TokenStream {
+        let name = Ident::new(LABEL_ENUM, span);
+        let variant = &label.ident;
+        quote_spanned!(span=> #name::#variant)
+    }
+
+    /// Replaces `label_addr!` in the tokens of other macros.
+    fn lower_tokens(&self, tokens: TokenStream) -> TokenStream {
+        let mut tokens = tokens.into_iter().peekable();
+        let mut lowered = TokenStream::new();
+        while let Some(token) = tokens.next() {
+            match token {
+                TokenTree::Ident(ident) if ident == "label_addr" || ident == "label_ref" => {
+                    let is_macro = matches!(tokens.peek(), Some(TokenTree::Punct(bang)) if bang.as_char() == '!');
+                    if is_macro {
+                        tokens.next();
+                        if let Some(TokenTree::Group(group)) = tokens.next() {
+                            let label: Lifetime = parse2(group.stream()).expect("label should have been checked");
+                            lowered.extend(Self::variant(&label, ident.span()));
+                        }
+                    } else {
+                        lowered.extend(Some(TokenTree::Ident(ident)));
+                    }
+                },
+                TokenTree::Group(group) => {
+                    let mut new_group = Group::new(group.delimiter(), self.lower_tokens(group.stream()));
+                    new_group.set_span(group.span());
+                    lowered.extend(Some(TokenTree::Group(new_group)));
+                },
+                token => lowered.extend(Some(token)),
+            }
+        }
+        lowered
+    }
+}
+
+impl<'l> VisitMut for Lowering<'l> {
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Macro(ExprMacro { attrs, mac }) = expr {
+            let span = mac.span();
+            if is_label_addr(&mac.path) {
+                let label: Lifetime = parse2(mac.tokens.clone()).expect("label should have been checked");
+                *expr = parse2(Self::variant(&label, span)).unwrap();
+                return;
+            } else if mac.path.is_ident("goto_dyn") {
+                let target = &mac.tokens;
+                let arms = self.labels.iter().map(|label| {
+                    let variant = Self::variant(label, span);
+                    quote_spanned!(span=> #variant => forward_goto!(#label),)
+                });
+                *expr = parse_quote_spanned!(span=> #(#attrs)* match #target { #(#arms)* });
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        mac.tokens = self.lower_tokens(std::mem::take(&mut mac.tokens));
+    }
+}
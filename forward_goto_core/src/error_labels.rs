@@ -0,0 +1,240 @@
+use syn::*;
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use proc_macro2::Span;
+use quote::ToTokens;
+use indexmap::IndexMap;
+use super::forms;
+use super::result::ErrInfo;
+
+/// The arguments of `forward_try!(expr, 'label)`.
+struct Try {
+    expr: Expr,
+    label: Lifetime,
+}
+
+impl Parse for Try {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let expr = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let label = input.parse()?;
+        Ok(Self { expr, label })
+    }
+}
+
+/// The arguments of `forward_label!('label, pat: Type)`, a label that binds the error of `forward_try!`.
+struct ErrorLabel {
+    label: Lifetime,
+    pat: Pat,
+    ty: Type,
+}
+
+impl Parse for ErrorLabel {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let label = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let pat = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let ty = input.parse()?;
+        Ok(Self { label, pat, ty })
+    }
+}
+
+/// Returns the name of the variable that holds the error for `label`.
+///
+/// The name is hygienic, so it cannot collide with the variables of the user.
+fn slot(label: &Lifetime) -> Ident {
+    Ident::new(&format!("__forward_goto_error_{}", label.ident), Span::mixed_site())
+}
+
+/// Lowers `forward_try!` and the labels that bind its error.
+///
+/// Every label `forward_label!('fail, e: E)` gets a variable `Option<E>`,
+/// which is declared at the start of the function:
+///
+/// ```text
+/// forward_try!(expr, 'fail)
+///
+/// match expr {
+///     Ok(value) => value,
+///     Err(err) => {
+///         let err = From::from(err);
+///         __forward_goto_error_fail = Some(err);
+///         forward_goto!('fail)
+///     },
+/// }
+///
+/// forward_label!('fail, e: E);
+///
+/// forward_label!('fail);
+/// let e: E = __forward_goto_error_fail.take().expect(...);
+/// ```
+///
+/// The statement in front of such a label has to diverge, so the `expect` only guards against
+/// gotos that other macros expand to.
+pub fn lower(block: &mut Block) -> std::result::Result<(), Vec<ErrInfo>> {
+    let mut scan = Scan::default();
+    scan.visit_block(block);
+    if scan.labels.is_empty() && scan.tries.is_empty() && scan.errors.is_empty() {
+        return Ok(());
+    }
+
+    let mut errors = scan.errors;
+    for (label, span) in &scan.tries {
+        if !scan.labels.contains_key(label) {
+            errors.push((*span, format!(
+                "forward_try! needs a label that binds the error, like `forward_label!({}, err: Error)`",
+                label
+            )));
+        }
+    }
+    for (label, span) in &scan.gotos {
+        if scan.labels.contains_key(label) {
+            errors.push((*span, format!(
+                "Label {} binds an error, so it can only be reached with forward_try!",
+                label
+            )));
+        }
+    }
+
+    let mut lowering = Lowering { errors };
+    lowering.visit_block_mut(block);
+    if !lowering.errors.is_empty() {
+        return Err(lowering.errors);
+    }
+
+    let decls: Vec<Stmt> = scan.labels
+        .iter()
+        .map(|(label, ty)| {
+            let slot = slot(label);
+            parse_quote_spanned!(label.span()=> let mut #slot: ::core::option::Option<#ty> = ::core::option::Option::None;)
+        })
+        .collect();
+    block.stmts.splice(0..0, decls);
+
+    Ok(())
+}
+
+/// Collects the labels that bind an error with their type, and the gotos to them.
+#[derive(Default)]
+struct Scan {
+    labels: IndexMap<Lifetime, Type>,
+    tries: Vec<(Lifetime, Span)>,
+    gotos: Vec<(Lifetime, Span)>,
+    errors: Vec<ErrInfo>,
+}
+
+impl<'ast> Visit<'ast> for Scan {
+    fn visit_item(&mut self, _item: &'ast Item) {}
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        if mac.path.is_ident("forward_try") {
+            match mac.parse_body::<Try>() {
+                Ok(args) => {
+                    self.tries.push((args.label, mac.span()));
+                    self.visit_expr(&args.expr);
+                },
+                Err(err) => self.errors.push((err.span(), err.to_string())),
+            }
+        } else if mac.path.is_ident("forward_goto") {
+            if let Ok(label) = mac.parse_body::<Lifetime>() {
+                self.gotos.push((label, mac.span()));
+            }
+        } else if mac.path.is_ident("forward_label") && !mac.tokens.is_empty() {
+            // plain labels are checked when they are rewritten
+            if parse2::<Lifetime>(mac.tokens.clone()).is_err() {
+                match mac.parse_body::<ErrorLabel>() {
+                    Ok(ErrorLabel { label, ty, .. }) => match self.labels.get(&label) {
+                        // a reused label name shares the variable, so the type has to be the same
+                        Some(other) if other.to_token_stream().to_string() != ty.to_token_stream().to_string() => {
+                            self.errors.push((ty.span(), format!(
+                                "Label {} binds errors of type `{}` and `{}`. Rename one of the labels",
+                                label, other.to_token_stream(), ty.to_token_stream()
+                            )));
+                        },
+                        Some(_) => (),
+                        None => {
+                            self.labels.insert(label, ty);
+                        },
+                    },
+                    Err(err) => self.errors.push((err.span(), err.to_string())),
+                }
+            }
+        }
+        visit::visit_macro(self, mac);
+    }
+}
+
+/// Replaces `forward_try!` and the labels that bind its error.
+struct Lowering {
+    errors: Vec<ErrInfo>,
+}
+
+impl VisitMut for Lowering {
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        let stmts = std::mem::take(&mut block.stmts);
+        for stmt in stmts {
+            match stmt {
+                Stmt::Semi(Expr::Macro(mac), _) if is_error_label(&mac.mac) => {
+                    let ErrorLabel { label, pat, ty } = mac.mac.parse_body().unwrap();
+                    let span = mac.span();
+                    // normal flow would reach the label without an error
+                    if !block.stmts.last().is_some_and(forms::diverges) {
+                        self.errors.push((span, format!(
+                            "The statements in front of label {} reach it without an error. \
+                            End them with `return`, or jump over the label",
+                            label
+                        )));
+                    }
+                    let attrs = &mac.attrs;
+                    let let_attrs = attrs.iter().filter(|attr| !forms::is_handler_marker(attr));
+                    let slot = slot(&label);
+                    let message = format!("label {} was reached without an error", label);
+                    block.stmts.push(parse_quote_spanned!(span=> #(#attrs)* forward_label!(#label);));
+                    block.stmts.push(parse_quote_spanned!(span=> #(#let_attrs)* let #pat: #ty = #slot.take().expect(#message);));
+                },
+                mut stmt => {
+                    self.visit_stmt_mut(&mut stmt);
+                    block.stmts.push(stmt);
+                },
+            }
+        }
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if let Expr::Macro(ExprMacro { attrs, mac }) = expr {
+            let span = mac.span();
+            if mac.path.is_ident("forward_try") {
+                let Try { expr: mut result, label } = mac.parse_body().unwrap();
+                self.visit_expr_mut(&mut result);
+                let slot = slot(&label);
+                *expr = parse_quote_spanned!(span=>
+                    #(#attrs)*
+                    match #result {
+                        ::core::result::Result::Ok(value) => value,
+                        ::core::result::Result::Err(err) => {
+                            #[allow(clippy::useless_conversion)]
+                            let err = ::core::convert::From::from(err);
+                            #slot = ::core::option::Option::Some(err);
+                            forward_goto!(#label)
+                        },
+                    }
+                );
+                return;
+            } else if is_error_label(mac) {
+                self.errors.push((span, "forward_label! can only bind an error as a statement".into()));
+                return;
+            }
+        }
+        visit_mut::visit_expr_mut(self, expr);
+    }
+}
+
+/// Returns whether `mac` is `forward_label!('label, pat: Type)`.
+fn is_error_label(mac: &Macro) -> bool {
+    mac.path.is_ident("forward_label") && mac.parse_body::<ErrorLabel>().is_ok()
+}
@@ -0,0 +1,566 @@
+use syn::*;
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::visit::Visit;
+use syn::visit_mut::{self, VisitMut};
+use quote::{quote_spanned, ToTokens};
+use proc_macro2::{Span, TokenStream};
+use std::collections::HashMap;
+use super::labels::LabelNames;
+use super::result;
+use super::scope::PatBindings;
+
+/// The arguments of `forward_goto_if!(cond, 'label)`.
+pub struct GotoIf {
+    pub cond: Expr,
+    pub label: Lifetime,
+}
+
+impl Parse for GotoIf {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let cond = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let label = input.parse()?;
+        Ok(Self { cond, label })
+    }
+}
+
+impl ToTokens for GotoIf {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.cond.to_tokens(tokens);
+        Token![,](self.label.span()).to_tokens(tokens);
+        self.label.to_tokens(tokens);
+    }
+}
+
+/// The arguments of `forward_goto_if_let!(pat = expr, 'label)`.
+///
+/// The goto is taken if `expr` does not match `pat`.
+/// Otherwise the bindings of `pat` are available for the rest of the block.
+pub struct GotoIfLet {
+    pub pat: Pat,
+    pub expr: Expr,
+    pub label: Lifetime,
+}
+
+impl Parse for GotoIfLet {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let pat = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let expr = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let label = input.parse()?;
+        Ok(Self { pat, expr, label })
+    }
+}
+
+impl ToTokens for GotoIfLet {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        self.pat.to_tokens(tokens);
+        Token![=](self.label.span()).to_tokens(tokens);
+        self.expr.to_tokens(tokens);
+        Token![,](self.label.span()).to_tokens(tokens);
+        self.label.to_tokens(tokens);
+    }
+}
+
+impl GotoIfLet {
+    /// Returns the `let` statement that binds the pattern or jumps to the label:
+    ///
+    /// ```text
+    /// let (a, mut b) = match expr { pat => (a, b), _ => forward_goto!('label) };
+    /// ```
+    ///
+    /// `bindings` are the bindings of `pat` with their mutability,
+    /// which has to be removed from `pat` already.
+    pub fn into_local(self, bindings: &[(Ident, Option<Token![mut]>)], attrs: Vec<Attribute>, span: Span) -> Stmt {
+        let Self { pat, expr, label } = self;
+        let idents = bindings.iter().map(|(ident, _)| ident);
+        let decls = bindings.iter().map(|(ident, mutability)| quote_spanned!(span=> #mutability #ident));
+
+        let mut stmt: Stmt = if bindings.len() == 1 {
+            parse_quote_spanned!(span=>
+                let #(#decls)* = match #expr {
+                    #pat => #(#idents)*,
+                    _ => forward_goto!(#label),
+                };
+            )
+        } else {
+            parse_quote_spanned!(span=>
+                let (#(#decls),*) = match #expr {
+                    #pat => (#(#idents),*),
+                    _ => forward_goto!(#label),
+                };
+            )
+        };
+
+        if let Stmt::Local(local) = &mut stmt {
+            local.attrs = attrs;
+        }
+        stmt
+    }
+}
+
+/// An arm of `forward_goto_match!`. Arms without a label fall through.
+pub struct GotoMatchArm {
+    pub pat: Pat,
+    pub guard: Option<Expr>,
+    pub label: Option<Lifetime>,
+}
+
+/// The arguments of `forward_goto_match!(expr { pat => 'label, ... })`.
+pub struct GotoMatch {
+    pub expr: Expr,
+    pub arms: Vec<GotoMatchArm>,
+}
+
+impl Parse for GotoMatch {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let expr = Expr::parse_without_eager_brace(input)?;
+        let content;
+        braced!(content in input);
+
+        let mut arms = Vec::new();
+        while !content.is_empty() {
+            let pat = parse_multi_pat(&content)?;
+            let guard = if content.peek(Token![if]) {
+                content.parse::<Token![if]>()?;
+                Some(content.parse()?)
+            } else {
+                None
+            };
+            let label = if content.peek(Token![=>]) {
+                content.parse::<Token![=>]>()?;
+                Some(content.parse()?)
+            } else {
+                None
+            };
+            arms.push(GotoMatchArm { pat, guard, label });
+
+            if !content.is_empty() {
+                content.parse::<Token![,]>()?;
+            }
+        }
+
+        Ok(Self { expr, arms })
+    }
+}
+
+impl ToTokens for GotoMatch {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        let expr = &self.expr;
+        let arms = self.arms.iter().map(|GotoMatchArm { pat, guard, label }| {
+            let guard = guard.as_ref().map(|guard| quote::quote!(if #guard));
+            let label = label.as_ref().map(|label| quote::quote!(=> #label));
+            quote::quote!(#pat #guard #label)
+        });
+        tokens.extend(quote::quote!(#expr { #(#arms),* }));
+    }
+}
+
+impl GotoMatch {
+    /// Returns the `match` that jumps to the label of the matching arm.
+    pub fn into_match(self, span: Span) -> Expr {
+        let expr = self.expr;
+        let arms = self.arms.into_iter().map(|GotoMatchArm { pat, guard, label }| {
+            let guard = guard.map(|guard| quote_spanned!(span=> if #guard));
+            match label {
+                Some(label) => quote_spanned!(span=> #pat #guard => forward_goto!(#label),),
+                None => quote_spanned!(span=> #pat #guard => {},),
+            }
+        });
+        parse_quote_spanned!(span=> match #expr { #(#arms)* })
+    }
+}
+
+/// Parses a pattern with alternatives `A | B`, like in a match arm.
+fn parse_multi_pat(input: ParseStream) -> Result<Pat> {
+    let leading_vert: Option<Token![|]> = input.parse()?;
+    let pat: Pat = input.parse()?;
+    if leading_vert.is_none() && !input.peek(Token![|]) {
+        return Ok(pat);
+    }
+
+    let mut cases = punctuated::Punctuated::new();
+    cases.push_value(pat);
+    while input.peek(Token![|]) {
+        cases.push_punct(input.parse()?);
+        cases.push_value(input.parse()?);
+    }
+    Ok(Pat::Or(PatOr { attrs: Vec::new(), leading_vert, cases }))
+}
+
+/// The arguments of `forward_handler!('label, { ... })`.
+///
+/// Like `forward_label!`, a handler can bind the error of `forward_try!`
+/// with `forward_handler!('label, pat: Type, { ... })`.
+pub struct Handler {
+    pub label: Lifetime,
+    pub binding: Option<(Pat, Type)>,
+    pub body: Block,
+}
+
+impl Parse for Handler {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let label = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let binding = if input.peek(token::Brace) {
+            None
+        } else {
+            let pat = input.parse()?;
+            input.parse::<Token![:]>()?;
+            let ty = input.parse()?;
+            input.parse::<Token![,]>()?;
+            Some((pat, ty))
+        };
+        let body = input.parse()?;
+        Ok(Self { label, binding, body })
+    }
+}
+
+/// Returns the macro of `stmt`, if it is `forward_handler!(...)`.
+fn handler_macro(stmt: &Stmt) -> Option<&ExprMacro> {
+    match stmt {
+        Stmt::Semi(Expr::Macro(mac), _) | Stmt::Expr(Expr::Macro(mac)) if mac.mac.path.is_ident("forward_handler") => Some(mac),
+        _ => None,
+    }
+}
+
+const HANDLER_MARKER: &str = "forward_goto_handler";
+
+/// Lowers the `forward_handler!` statements of all blocks in `block`.
+///
+/// Normal control flow jumps over a handler to a generated label behind it,
+/// so the statements around the handler stay in the same block:
+///
+/// ```text
+/// { a; forward_handler!('fail, { h }); b; c }
+///
+/// {
+///     a;
+///     forward_goto!('_handler0);
+///     #[forward_goto_handler] forward_label!('fail);
+///     let _: Infallible = { h };
+///     forward_label!('_handler0);
+///     b;
+///     c
+/// }
+/// ```
+///
+/// The handler has to diverge, which is checked by rustc through its type.
+/// The marked label tells the [`ScopeChecker`](crate::scope::ScopeChecker) that the bindings
+/// its gotos skip are still available behind the handler.
+pub fn lower_handlers(block: &mut Block, names: &mut LabelNames) -> result::Result<()> {
+    struct Lowering<'n> {
+        names: &'n mut LabelNames,
+        error: Option<result::ErrInfo>,
+    }
+
+    impl<'n> VisitMut for Lowering<'n> {
+        fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+        fn visit_block_mut(&mut self, block: &mut Block) {
+            if block.stmts.iter().any(|stmt| handler_macro(stmt).is_some()) {
+                for stmt in std::mem::take(&mut block.stmts) {
+                    match handler_macro(&stmt).map(|mac| self.lower(mac)) {
+                        Some(Ok(stmts)) => block.stmts.extend(stmts),
+                        Some(Err(err)) => {
+                            self.error.get_or_insert(err);
+                            return;
+                        },
+                        None => block.stmts.push(stmt),
+                    }
+                }
+            }
+            visit_mut::visit_block_mut(self, block);
+        }
+    }
+
+    impl<'n> Lowering<'n> {
+        /// Returns the statements that replace the handler `mac`.
+        fn lower(&mut self, mac: &ExprMacro) -> result::Result<Vec<Stmt>> {
+            let span = mac.span();
+            let attrs = &mac.attrs;
+            let Handler { label, binding, body } = mac.mac.parse_body().map_err(|err| (err.span(), err.to_string()))?;
+            let binding = binding.map(|(pat, ty)| quote_spanned!(span=> , #pat: #ty));
+            let end = self.names.new_handler_end(span);
+            let marker = Ident::new(HANDLER_MARKER, Span::call_site());
+
+            // a handler that does not diverge is reported as a type mismatch at its body
+            let never = quote_spanned!(body.span()=> ::core::convert::Infallible);
+            let block: Block = parse_quote_spanned!(span=> {
+                #[allow(unreachable_code)]
+                forward_goto!(#end);
+                #(#attrs)*
+                #[#marker]
+                forward_label!(#label #binding);
+                #[allow(unreachable_code, clippy::diverging_sub_expression)]
+                let _: #never = #body;
+                forward_label!(#end);
+            });
+            Ok(block.stmts)
+        }
+    }
+
+    let mut lowering = Lowering { names, error: None };
+    lowering.visit_block_mut(block);
+    lowering.error.map_or(Ok(()), Err)
+}
+
+/// Keeps the bindings in `carried` available behind the lowered handlers, by the name of the label behind each handler.
+///
+/// A binding that is declared after a goto to a handler goes out of scope at the handler.
+/// Usually its `let` is hoisted in front of the wrapper, but that declaration would hide
+/// a binding with the same name that the wrapper still uses, e.g. in `let a = match a { ... };`.
+/// Such bindings are moved over the handler instead, which only normal flow passes:
+///
+/// ```text
+/// let __forward_goto_carried_a;
+/// ...
+/// { __forward_goto_carried_a = a; }
+/// forward_goto!('_handler0);
+/// ...
+/// forward_label!('_handler0);
+/// let a = __forward_goto_carried_a;
+/// ```
+pub fn carry_handler_bindings(block: &mut Block, carried: &HashMap<Ident, Vec<(Ident, bool)>>) {
+    struct Carry<'c> {
+        carried: &'c HashMap<Ident, Vec<(Ident, bool)>>,
+    }
+
+    impl<'c> VisitMut for Carry<'c> {
+        fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+        fn visit_block_mut(&mut self, block: &mut Block) {
+            visit_mut::visit_block_mut(self, block);
+
+            let mut decls = Vec::new();
+            for stmt in std::mem::take(&mut block.stmts) {
+                let carried = |label: Lifetime| {
+                    let bindings = self.carried.get(&label.ident)?;
+                    Some((label, bindings))
+                };
+                let goto = stmt_label(&stmt, "forward_goto").and_then(carried);
+                let label = stmt_label(&stmt, "forward_label").and_then(carried);
+                if let Some((end, bindings)) = goto {
+                    let slots: Vec<_> = bindings.iter().map(|(ident, _)| carried_slot(&end, ident)).collect();
+                    let idents = bindings.iter().map(|(ident, _)| ident);
+                    decls.extend(slots.iter().map(|slot| -> Stmt { parse_quote!(let #slot;) }));
+                    block.stmts.push(parse_quote_spanned!(end.span()=> #[allow(unreachable_code)] { #(#slots = #idents;)* }));
+                }
+                block.stmts.push(stmt);
+                if let Some((end, bindings)) = label {
+                    for (ident, mutable) in bindings {
+                        let slot = carried_slot(&end, ident);
+                        let mutability = if *mutable { Some(quote_spanned!(ident.span()=> mut)) } else { None };
+                        block.stmts.push(parse_quote_spanned!(ident.span()=> let #mutability #ident = #slot;));
+                    }
+                }
+            }
+            block.stmts.splice(0..0, decls);
+        }
+    }
+
+    /// The variable that carries `ident` over the handler in front of `end`, which the user cannot name.
+    fn carried_slot(end: &Lifetime, ident: &Ident) -> Ident {
+        Ident::new(&format!("__forward_goto_carried{}_{}", end.ident, ident), Span::mixed_site())
+    }
+
+    if !carried.is_empty() {
+        Carry { carried }.visit_block_mut(block);
+    }
+}
+
+/// Returns whether `attr` marks the label of a lowered `forward_handler!`.
+pub fn is_handler_marker(attr: &Attribute) -> bool {
+    attr.path.is_ident(HANDLER_MARKER)
+}
+
+/// Returns the label of `stmt`, if it is the label of a lowered `forward_handler!`.
+pub fn handler_label(stmt: &Stmt) -> Option<Lifetime> {
+    match stmt {
+        Stmt::Semi(Expr::Macro(mac), _) if mac.attrs.iter().any(is_handler_marker) => stmt_label(stmt, "forward_label"),
+        _ => None,
+    }
+}
+
+/// Returns the label of `stmt`, if it is `name!('label);`, e.g. a plain `forward_label!`.
+pub fn stmt_label(stmt: &Stmt, name: &str) -> Option<Lifetime> {
+    match stmt {
+        Stmt::Semi(Expr::Macro(mac), _) if mac.mac.path.is_ident(name) => parse2(mac.mac.tokens.clone()).ok(),
+        _ => None,
+    }
+}
+
+/// The macros that never complete normally.
+const DIVERGING_MACROS: &[&str] = &["forward_goto", "goto_dyn", "panic", "unreachable", "todo", "unimplemented"];
+
+/// Returns whether `stmt` never completes normally, e.g. because it ends with `return` or a goto.
+///
+/// This is a syntactic approximation: it might miss statements that rustc knows to diverge,
+/// like calls of functions that return `!`, but every statement it accepts diverges.
+pub fn diverges(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Local(Local { init: Some((_, init)), .. }) => expr_diverges(init),
+        Stmt::Expr(expr) | Stmt::Semi(expr, _) => expr_diverges(expr),
+        _ => false,
+    }
+}
+
+fn expr_diverges(expr: &Expr) -> bool {
+    match expr {
+        Expr::Return(_) | Expr::Break(_) | Expr::Continue(_) => true,
+        Expr::Macro(mac) => mac.mac.path.segments.last().is_some_and(|segment| {
+            DIVERGING_MACROS.iter().any(|name| segment.ident == name)
+        }),
+        // a `break` in a labeled block might leave just the block
+        Expr::Block(ExprBlock { label: None, block, .. }) | Expr::Unsafe(ExprUnsafe { block, .. }) => block.stmts.iter().any(diverges),
+        Expr::If(ExprIf { cond, then_branch, else_branch, .. }) => {
+            expr_diverges(cond) || match else_branch {
+                Some((_, else_branch)) => then_branch.stmts.iter().any(diverges) && expr_diverges(else_branch),
+                None => false,
+            }
+        },
+        Expr::Match(ExprMatch { expr, arms, .. }) => expr_diverges(expr) || arms.iter().all(|arm| expr_diverges(&arm.body)),
+        Expr::Loop(ExprLoop { body, .. }) => !contains_break(body),
+        Expr::Paren(ExprParen { expr, .. }) | Expr::Group(ExprGroup { expr, .. }) => expr_diverges(expr),
+        _ => false,
+    }
+}
+
+/// Returns whether `block` contains a `break` outside of closures, async blocks and items.
+///
+/// Gotos do not count, because they never continue behind the loop.
+fn contains_break(block: &Block) -> bool {
+    struct Breaks(bool);
+
+    impl<'ast> Visit<'ast> for Breaks {
+        fn visit_item(&mut self, _item: &'ast Item) {}
+        fn visit_expr_closure(&mut self, _closure: &'ast ExprClosure) {}
+        fn visit_expr_async(&mut self, _async_block: &'ast ExprAsync) {}
+
+        fn visit_expr_break(&mut self, _break: &'ast ExprBreak) {
+            self.0 = true;
+        }
+    }
+
+    let mut breaks = Breaks(false);
+    breaks.visit_block(block);
+    breaks.0
+}
+
+/// Returns the macro of `stmt`, if it is `forward_goto_if_let!(...);`.
+pub fn goto_if_let_macro(stmt: &Stmt) -> Option<&ExprMacro> {
+    match stmt {
+        Stmt::Semi(Expr::Macro(mac), _) if mac.mac.path.is_ident("forward_goto_if_let") => Some(mac),
+        _ => None,
+    }
+}
+
+/// Returns whether `expr` is `fallthrough!()`.
+fn is_fallthrough(expr: &Expr) -> bool {
+    matches!(expr, Expr::Macro(mac) if mac.mac.path.is_ident("fallthrough") && mac.mac.tokens.is_empty())
+}
+
+/// Removes `fallthrough!()` from the end of `body` and returns its span.
+fn take_fallthrough(body: &mut Expr) -> Option<Span> {
+    if is_fallthrough(body) {
+        let span = body.span();
+        *body = parse_quote_spanned!(span=> {});
+        return Some(span);
+    }
+
+    match body {
+        Expr::Block(ExprBlock { block, label: None, .. }) => match block.stmts.last() {
+            Some(Stmt::Expr(expr)) | Some(Stmt::Semi(expr, _)) if is_fallthrough(expr) => {
+                let span = expr.span();
+                block.stmts.pop();
+                Some(span)
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Lowers a `match` statement with `fallthrough!()` at the end of some arms.
+///
+/// The arms that are continued into are moved behind the `match` in their order,
+/// each one behind a label that is the target of the arm before it:
+///
+/// ```text
+/// match e { A => { a; fallthrough!() }, B => b, C => c }
+///
+/// match e { A => { a; forward_goto!('b) }, B => forward_goto!('b), C => c }
+/// forward_goto!('end);
+/// forward_label!('b);
+/// { b };
+/// forward_label!('end);
+/// ```
+///
+/// Returns the statements that have to follow `stmt`, or nothing if there is no `fallthrough!()`.
+/// `new_label` creates a new label for the given span.
+pub fn lower_fallthrough(stmt: &mut Stmt, mut new_label: impl FnMut(Span) -> Lifetime) -> result::Result<Vec<Stmt>> {
+    let expr_match = match stmt {
+        Stmt::Expr(Expr::Match(expr_match)) | Stmt::Semi(Expr::Match(expr_match), _) => expr_match,
+        _ => return Ok(Vec::new()),
+    };
+
+    let fallthroughs: Vec<_> = expr_match.arms
+        .iter_mut()
+        .map(|arm| take_fallthrough(&mut arm.body))
+        .collect();
+    if fallthroughs.iter().all(Option::is_none) {
+        return Ok(Vec::new());
+    }
+    if let Some(Some(span)) = fallthroughs.last() {
+        return Err((*span, "fallthrough!() cannot be used in the last match arm".into()));
+    }
+
+    let span = expr_match.match_token.span;
+    let end = new_label(span);
+    let mut after: Vec<Stmt> = vec![parse_quote_spanned!(span=> #[allow(unreachable_code)] forward_goto!(#end);)];
+
+    for i in 0..expr_match.arms.len() {
+        let moved = match i.checked_sub(1).and_then(|prev| fallthroughs[prev]) {
+            Some(span) => span,
+            None => continue,
+        };
+
+        let arm = &mut expr_match.arms[i];
+        if arm.attrs.iter().any(|attr| attr.path.is_ident("cfg")) {
+            return Err((moved, "fallthrough!() cannot continue into an arm with #[cfg]".into()));
+        }
+        let mut bindings = PatBindings { idents: Vec::new(), refutable: true };
+        bindings.visit_pat(&arm.pat);
+        if let Some(binding) = bindings.idents.first() {
+            return Err((moved, format!(
+                "fallthrough!() cannot continue into an arm that binds `{}`, because its pattern did not match",
+                binding
+            )));
+        }
+
+        // the arm jumps to its body behind the match
+        let label = new_label(moved);
+        let body = std::mem::replace(&mut *arm.body, parse_quote_spanned!(moved=> forward_goto!(#label)));
+        if arm.comma.is_none() {
+            arm.comma = Some(Token![,](moved));
+        }
+
+        // so does the arm before, if its body is still in the match
+        if let Expr::Block(ExprBlock { block, .. }) = &mut *expr_match.arms[i - 1].body {
+            block.stmts.push(parse_quote_spanned!(moved=> forward_goto!(#label);));
+        }
+
+        after.push(parse_quote_spanned!(moved=> forward_label!(#label);));
+        after.push(Stmt::Semi(body, Token![;](moved)));
+        if fallthroughs[i].is_none() {
+            after.push(parse_quote_spanned!(span=> #[allow(unreachable_code)] forward_goto!(#end);));
+        }
+    }
+
+    // the last body continues directly at the end label
+    after.pop();
+    after.push(parse_quote_spanned!(span=> forward_label!(#end);));
+    Ok(after)
+}
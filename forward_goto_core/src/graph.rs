@@ -0,0 +1,228 @@
+use std::fmt::Write;
+use super::report::{Report, StmtInfo};
+use super::spans;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NodeKind {
+    /// Statements enclosed by a generated wrapper.
+    Statements,
+    /// A goto label.
+    Label,
+    /// Statements after labels that were lifted out of their blocks.
+    Continuation,
+    /// The end of a generated wrapper, where the code after it continues.
+    Exit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EdgeKind {
+    /// A goto jumping to its label.
+    Goto,
+    /// Code reaching the next node without a jump.
+    Fallthrough,
+    /// A generated break at the end of a continuation.
+    Break,
+}
+
+impl NodeKind {
+    fn name(self) -> &'static str {
+        match self {
+            NodeKind::Statements => "statements",
+            NodeKind::Label => "label",
+            NodeKind::Continuation => "continuation",
+            NodeKind::Exit => "exit",
+        }
+    }
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Goto => "goto",
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Break => "break",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub id: String,
+    pub kind: NodeKind,
+    /// First and last source line, if known.
+    pub lines: Option<(usize, usize)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Edge {
+    pub from: String,
+    pub to: String,
+    pub kind: EdgeKind,
+    /// Source line of the goto, if known.
+    pub line: Option<usize>,
+}
+
+/// The control-flow between gotos, labels and the generated wrappers of a function.
+///
+/// Built from a [`Report`], it can be written as Graphviz DOT or as JSON.
+#[derive(Clone, Debug, Default)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+fn lines(stmts: &[StmtInfo]) -> Option<(usize, usize)> {
+    let (start, _) = stmts.first()?.lines?;
+    let (_, end) = stmts.last()?.lines?;
+    Some((start, end))
+}
+
+impl Graph {
+    pub fn new(report: &Report) -> Self {
+        let mut graph = Graph::default();
+
+        for (label, span) in report.labels() {
+            let lines = spans::line(*span).map(|line| (line, line));
+            graph.add_node(label.to_string(), NodeKind::Label, lines);
+        }
+
+        for (i, wrap) in report.wraps().iter().enumerate() {
+            let statements = format!("wrap{}", i);
+            let exit = format!("exit{}", i);
+            graph.add_node(statements.clone(), NodeKind::Statements, lines(&wrap.wrapped));
+            graph.add_node(exit.clone(), NodeKind::Exit, None);
+            graph.add_edge(&statements, &exit, EdgeKind::Fallthrough, None);
+
+            for label in &wrap.labels {
+                graph.add_edge(&statements, &label.to_string(), EdgeKind::Fallthrough, None);
+            }
+
+            for (_, stmts, outgoing) in &wrap.lifted {
+                graph.add_node(outgoing.to_string(), NodeKind::Continuation, lines(stmts));
+            }
+
+            // every label and continuation flows into the continuation that follows it,
+            // the last one leaves the wrapper
+            let arrivals = wrap.labels.iter().chain(wrap.lifted.iter().map(|(_, _, outgoing)| outgoing));
+            for arrival in arrivals {
+                let kind = if wrap.labels.contains(arrival) { EdgeKind::Fallthrough } else { EdgeKind::Break };
+                let next = wrap.lifted
+                    .iter()
+                    .find(|(incomings, _, _)| incomings.contains(arrival))
+                    .map_or_else(|| exit.clone(), |(_, _, outgoing)| outgoing.to_string());
+                graph.add_edge(&arrival.to_string(), &next, kind, None);
+            }
+        }
+
+        for (label, span) in report.gotos() {
+            let line = spans::line(*span);
+            let from = graph.innermost_node(line).unwrap_or_else(|| {
+                // without line information, the goto is attributed to the wrapper of its label
+                let index = report.wraps().iter().position(|wrap| wrap.labels.contains(label)).unwrap_or(0);
+                format!("wrap{}", index)
+            });
+            graph.add_edge(&from, &label.to_string(), EdgeKind::Goto, line);
+        }
+
+        graph
+    }
+
+    fn add_node(&mut self, id: String, kind: NodeKind, lines: Option<(usize, usize)>) {
+        if self.nodes.iter().all(|node| node.id != id) {
+            self.nodes.push(Node { id, kind, lines });
+        }
+    }
+
+    fn add_edge(&mut self, from: &str, to: &str, kind: EdgeKind, line: Option<usize>) {
+        self.edges.push(Edge { from: from.to_owned(), to: to.to_owned(), kind, line });
+    }
+
+    /// Returns the statement or continuation node with the smallest line range that contains `line`.
+    fn innermost_node(&self, line: Option<usize>) -> Option<String> {
+        let line = line?;
+        self.nodes
+            .iter()
+            .filter(|node| matches!(node.kind, NodeKind::Statements | NodeKind::Continuation))
+            .filter_map(|node| node.lines.map(|lines| (node, lines)))
+            .filter(|(_, (start, end))| *start <= line && line <= *end)
+            .min_by_key(|(_, (start, end))| end - start)
+            .map(|(node, _)| node.id.clone())
+    }
+
+    /// Writes the graph of the function `name` in the Graphviz DOT format.
+    pub fn to_dot(&self, name: &str) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph \"{}\" {{", escape(name)).unwrap();
+
+        for node in &self.nodes {
+            let shape = match node.kind {
+                NodeKind::Statements | NodeKind::Continuation => "box",
+                NodeKind::Label => "ellipse",
+                NodeKind::Exit => "point",
+            };
+            let text = match node.lines {
+                Some((start, end)) if start == end => format!("{}\nline {}", node.id, start),
+                Some((start, end)) => format!("{}\nlines {}-{}", node.id, start, end),
+                None => node.id.clone(),
+            };
+            writeln!(dot, "    \"{}\" [shape={}, label=\"{}\"];", escape(&node.id), shape, escape(&text)).unwrap();
+        }
+
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::Goto => "bold",
+                EdgeKind::Fallthrough => "solid",
+                EdgeKind::Break => "dashed",
+            };
+            let text = match edge.line {
+                Some(line) => format!("{} (line {})", edge.kind.name(), line),
+                None => edge.kind.name().to_owned(),
+            };
+            writeln!(dot, "    \"{}\" -> \"{}\" [style={}, label=\"{}\"];", escape(&edge.from), escape(&edge.to), style, escape(&text)).unwrap();
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Writes the graph of the function `name` as a JSON object.
+    pub fn to_json(&self, name: &str) -> String {
+        let nodes: Vec<_> = self.nodes
+            .iter()
+            .map(|node| {
+                let lines = node.lines.map_or_else(|| "null".to_owned(), |(start, end)| format!("[{}, {}]", start, end));
+                format!("{{\"id\": \"{}\", \"kind\": \"{}\", \"lines\": {}}}", escape(&node.id), node.kind.name(), lines)
+            })
+            .collect();
+
+        let edges: Vec<_> = self.edges
+            .iter()
+            .map(|edge| {
+                let line = edge.line.map_or_else(|| "null".to_owned(), |line| line.to_string());
+                format!(
+                    "{{\"from\": \"{}\", \"to\": \"{}\", \"kind\": \"{}\", \"line\": {}}}",
+                    escape(&edge.from), escape(&edge.to), edge.kind.name(), line
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"function\": \"{}\", \"nodes\": [{}], \"edges\": [{}]}}\n",
+            escape(name), nodes.join(", "), edges.join(", ")
+        )
+    }
+}
+
+/// Escapes `text` for a string literal in DOT and JSON.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,322 @@
+use syn::*;
+use std::collections::{HashMap, HashSet};
+use proc_macro2::{Delimiter, Span, TokenStream, TokenTree};
+use quote::ToTokens;
+
+/// Hands out the names of all labels that appear in the rewritten function.
+///
+/// Generated labels must neither collide with each other nor with
+/// loop labels or lifetimes of the user. Therefore all lifetimes in the
+/// function are collected up front and every new name is checked against them.
+/// Goto labels of the user keep their name, unless it is already taken
+/// by a lifetime or loop label, in which case they are renamed as well.
+///
+/// The names of all labels `forward_label!('label)` are known up front,
+/// because other macros may name them as goto targets before they appear.
+pub struct LabelNames {
+    taken: HashSet<String>,
+    labels: HashSet<String>,
+    internal: HashSet<String>,
+    user_labels: HashMap<String, String>,
+    generated: HashSet<Ident>,
+    next_id: u32,
+}
+
+impl LabelNames {
+    pub fn new(sig: &Signature, body: &Block) -> Self {
+        let tokens = quote::quote!(#sig #body);
+        let mut labels = HashSet::new();
+        collect_label_names(tokens.clone(), &mut labels);
+        let mut taken = HashSet::new();
+        collect_lifetimes(tokens, &mut taken, &labels);
+        Self {
+            taken,
+            labels,
+            internal: HashSet::new(),
+            user_labels: HashMap::new(),
+            generated: HashSet::new(),
+            next_id: 0,
+        }
+    }
+
+    fn fresh_name(&mut self, base: &str) -> String {
+        loop {
+            let name = format!("{}{}", base, self.next_id);
+            self.next_id += 1;
+            if self.taken.insert(name.clone()) {
+                return name;
+            }
+        }
+    }
+
+    /// Creates a new label for a continuation.
+    pub fn new_continuation(&mut self, span: Span) -> Lifetime {
+        let name = self.fresh_name("_continuation");
+        let lifetime = new_lifetime(&name, span);
+        self.generated.insert(lifetime.ident.clone());
+        lifetime
+    }
+
+    /// Creates a new label for the start of a match arm that is reached by `fallthrough!()`.
+    ///
+    /// It is used like a goto label of the user and maps to itself.
+    pub fn new_fallthrough(&mut self, span: Span) -> Lifetime {
+        self.new_internal("_fallthrough", span)
+    }
+
+    /// Creates a new label behind the body of a `forward_handler!`, which skips the handler.
+    ///
+    /// It is used like a goto label of the user and maps to itself.
+    pub fn new_handler_end(&mut self, span: Span) -> Lifetime {
+        self.new_internal("_handler", span)
+    }
+
+    fn new_internal(&mut self, base: &str, span: Span) -> Lifetime {
+        let name = self.fresh_name(base);
+        self.user_labels.insert(name.clone(), name.clone());
+        self.internal.insert(name.clone());
+        let lifetime = new_lifetime(&name, span);
+        self.generated.insert(lifetime.ident.clone());
+        lifetime
+    }
+
+    /// Returns the label that will be generated for the goto label `label` of the user.
+    pub fn user_label(&mut self, label: &Lifetime) -> Lifetime {
+        let user_name = label.ident.to_string();
+
+        let name = match self.user_labels.get(&user_name) {
+            Some(name) => name.clone(),
+            None => {
+                let name = if self.taken.insert(user_name.clone()) {
+                    user_name.clone()
+                } else {
+                    self.fresh_name(&format!("{}_", user_name))
+                };
+                self.user_labels.insert(user_name, name.clone());
+                self.generated.insert(Ident::new(&name, label.ident.span()));
+                name
+            },
+        };
+
+        new_lifetime(&name, label.ident.span())
+    }
+
+    /// Releases the user name that maps to the generated label `label`, after all gotos to it were resolved.
+    ///
+    /// The next goto or label with that name starts a new region and gets a new label,
+    /// so that the generated loops of sequential regions never share a name.
+    pub fn retire(&mut self, label: &Lifetime) {
+        self.user_labels.retain(|_, name| label.ident != name);
+    }
+
+    /// Returns whether `label` was created by [`new_fallthrough`](Self::new_fallthrough)
+    /// or [`new_handler_end`](Self::new_handler_end), so the user does not know it.
+    pub fn is_internal(&self, label: &Lifetime) -> bool {
+        self.internal.contains(&label.ident.to_string())
+    }
+
+    /// Returns the names of all labels `forward_label!('label)` and `forward_handler!('label, ...)` in the function.
+    pub fn labels(&self) -> &HashSet<String> {
+        &self.labels
+    }
+
+    /// Takes the names of all labels that were handed out so far.
+    pub fn take_generated(&mut self) -> HashSet<Ident> {
+        std::mem::take(&mut self.generated)
+    }
+}
+
+fn new_lifetime(name: &str, span: Span) -> Lifetime {
+    Lifetime {
+        apostrophe: span,
+        ident: Ident::new(name, span),
+    }
+}
+
+/// Collects the names of all labels `forward_label!('label)` and `forward_handler!('label, ...)` in `tokens`.
+pub fn collect_label_names(tokens: TokenStream, labels: &mut HashSet<String>) {
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            TokenTree::Ident(ident) if ident == "forward_label" || ident == "forward_handler" => {
+                let is_macro = matches!(tokens.peek(), Some(TokenTree::Punct(punct)) if punct.as_char() == '!');
+                if is_macro {
+                    tokens.next();
+                    if let Some(TokenTree::Group(group)) = tokens.next() {
+                        let mut args = group.stream().into_iter();
+                        if let (Some(TokenTree::Punct(_)), Some(TokenTree::Ident(ident))) = (args.next(), args.next()) {
+                            labels.insert(ident.to_string());
+                        }
+                    }
+                }
+            },
+            TokenTree::Group(group) => collect_label_names(group.stream(), labels),
+            _ => (),
+        }
+    }
+}
+
+/// Splits the tokens of a macro into its arguments, which are separated by top-level commas.
+fn macro_args(tokens: TokenStream) -> Vec<Vec<TokenTree>> {
+    let mut args = vec![Vec::new()];
+    for token in tokens {
+        match &token {
+            TokenTree::Punct(punct) if punct.as_char() == ',' => args.push(Vec::new()),
+            _ => args.last_mut().unwrap().push(token),
+        }
+    }
+    args
+}
+
+/// Returns the lifetime, if `arg` is nothing but a lifetime `'label`.
+fn lone_lifetime(arg: &[TokenTree]) -> Option<Lifetime> {
+    match arg {
+        [TokenTree::Punct(punct), TokenTree::Ident(ident)] if punct.as_char() == '\'' => {
+            Some(Lifetime { apostrophe: punct.span(), ident: ident.clone() })
+        },
+        _ => None,
+    }
+}
+
+/// Returns the labels in `labels` that are arguments of a macro the rewriter does not know.
+///
+/// Such a macro might expand to a goto, e.g. `check!(x > 0, 'fail)`.
+/// Only arguments that consist of the label alone count, so a lifetime
+/// in a type like `&'a str` is never taken for a goto.
+pub fn macro_labels(tokens: &TokenStream, labels: &HashSet<String>) -> Vec<Lifetime> {
+    let mut found: Vec<Lifetime> = Vec::new();
+    for arg in macro_args(tokens.clone()) {
+        if let Some(label) = lone_lifetime(&arg) {
+            if labels.contains(&label.ident.to_string()) && !found.iter().any(|found| found.ident == label.ident) {
+                found.push(label);
+            }
+        }
+    }
+    found
+}
+
+/// Renames the labels in the arguments of a macro to the generated labels in `renamed`.
+///
+/// Like in [`macro_labels`], only arguments that consist of the label alone are renamed.
+/// The new labels keep the span of the old ones, so they resolve in the same hygiene context.
+pub fn rename_macro_labels(tokens: TokenStream, renamed: &HashMap<String, Lifetime>) -> TokenStream {
+    fn flush(arg: &mut Vec<TokenTree>, renamed: &HashMap<String, Lifetime>, output: &mut TokenStream) {
+        let label = lone_lifetime(arg).and_then(|label| Some((label.apostrophe, renamed.get(&label.ident.to_string())?)));
+        match label {
+            Some((span, label)) => new_lifetime(&label.ident.to_string(), span).to_tokens(output),
+            None => output.extend(arg.iter().cloned()),
+        }
+        arg.clear();
+    }
+
+    let mut output = TokenStream::new();
+    let mut arg = Vec::new();
+    for token in tokens {
+        match &token {
+            TokenTree::Punct(punct) if punct.as_char() == ',' => {
+                flush(&mut arg, renamed, &mut output);
+                output.extend(Some(token));
+            },
+            _ => arg.push(token),
+        }
+    }
+    flush(&mut arg, renamed, &mut output);
+    output
+}
+
+const KEYWORDS: &[&str] = &["if", "while", "match", "return", "break", "in", "else", "let", "yield"];
+
+/// Collects the names of all lifetimes and loop labels in `tokens`.
+///
+/// The arguments of `forward_goto!` and `forward_label!`, the labels at the end
+/// of `forward_goto_if!` and `forward_goto_if_let!` and the labels after `=>` in `forward_goto_match!`
+/// are skipped, because those labels are never emitted under the name of the user
+/// if the name is already taken by anything else.
+/// For the same reason, the `labels` that are arguments of other macros are skipped.
+fn collect_lifetimes(tokens: TokenStream, taken: &mut HashSet<String>, labels: &HashSet<String>) {
+    let mut tokens = tokens.into_iter().peekable();
+    let mut after_ident = false;
+    while let Some(token) = tokens.next() {
+        // `if !(cond)` is not a macro call
+        let is_ident = matches!(&token, TokenTree::Ident(ident) if !KEYWORDS.iter().any(|keyword| ident == keyword));
+        match token {
+            TokenTree::Punct(punct) if punct.as_char() == '!' && after_ident => {
+                if let Some(TokenTree::Group(_)) = tokens.peek() {
+                    if let Some(TokenTree::Group(group)) = tokens.next() {
+                        for arg in macro_args(group.stream()) {
+                            let is_label = lone_lifetime(&arg).is_some_and(|label| labels.contains(&label.ident.to_string()));
+                            if !is_label {
+                                collect_lifetimes(arg.into_iter().collect(), taken, labels);
+                            }
+                        }
+                    }
+                }
+            },
+            TokenTree::Punct(punct) if punct.as_char() == '\'' => {
+                if let Some(TokenTree::Ident(ident)) = tokens.peek() {
+                    taken.insert(ident.to_string());
+                }
+            },
+            TokenTree::Ident(ident) if ident == "forward_goto" || ident == "forward_label" => {
+                let is_macro = matches!(tokens.peek(), Some(TokenTree::Punct(punct)) if punct.as_char() == '!');
+                if is_macro {
+                    tokens.next();
+                    tokens.next();
+                }
+            },
+            TokenTree::Ident(ident) if ident == "forward_goto_if" || ident == "forward_goto_if_let" => {
+                let is_macro = matches!(tokens.peek(), Some(TokenTree::Punct(punct)) if punct.as_char() == '!');
+                if is_macro {
+                    tokens.next();
+                    if let Some(TokenTree::Group(group)) = tokens.next() {
+                        // everything in front of the label
+                        let mut args: Vec<_> = group.stream().into_iter().collect();
+                        args.truncate(args.len().saturating_sub(2));
+                        collect_lifetimes(args.into_iter().collect(), taken, labels);
+                    }
+                }
+            },
+            TokenTree::Ident(ident) if ident == "forward_goto_match" => {
+                let is_macro = matches!(tokens.peek(), Some(TokenTree::Punct(punct)) if punct.as_char() == '!');
+                if is_macro {
+                    tokens.next();
+                    if let Some(TokenTree::Group(group)) = tokens.next() {
+                        // the arms are the last group, everything before is the matched expression
+                        let mut args: Vec<_> = group.stream().into_iter().collect();
+                        if let Some(TokenTree::Group(arms)) = args.last() {
+                            if arms.delimiter() == Delimiter::Brace {
+                                collect_arm_lifetimes(arms.stream(), taken, labels);
+                                args.pop();
+                            }
+                        }
+                        collect_lifetimes(args.into_iter().collect(), taken, labels);
+                    }
+                }
+            },
+            TokenTree::Group(group) => collect_lifetimes(group.stream(), taken, labels),
+            _ => (),
+        }
+        after_ident = is_ident;
+    }
+}
+
+/// Collects the lifetimes in the arms of `forward_goto_match!`, except for the labels after `=>`.
+fn collect_arm_lifetimes(tokens: TokenStream, taken: &mut HashSet<String>, labels: &HashSet<String>) {
+    let mut tokens = tokens.into_iter().peekable();
+    let mut after_arrow = false;
+    while let Some(token) = tokens.next() {
+        match &token {
+            TokenTree::Punct(punct) if punct.as_char() == '\'' && after_arrow => {
+                tokens.next();
+            },
+            TokenTree::Punct(punct) if punct.as_char() == '\'' => {
+                if let Some(TokenTree::Ident(ident)) = tokens.peek() {
+                    taken.insert(ident.to_string());
+                }
+            },
+            TokenTree::Group(group) => collect_lifetimes(group.stream(), taken, labels),
+            _ => (),
+        }
+        after_arrow = matches!(&token, TokenTree::Punct(punct) if punct.as_char() == '>');
+    }
+}
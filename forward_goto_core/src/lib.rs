@@ -0,0 +1,549 @@
+//! The rewriting engine behind the `forward_goto` crate.
+//!
+//! [`rewrite_fn`] rewrites the gotos `forward_goto!('label)` and labels `forward_label!('label)`
+//! in a parsed function into labeled loops and breaks.
+//! The `#[rewrite_forward_goto]` attribute of `forward_goto` is a thin wrapper around it,
+//! but the engine can also be used directly, e.g. by code generators or build scripts.
+//!
+//! ```
+//! use syn::{parse_quote, ItemFn};
+//!
+//! let mut item: ItemFn = parse_quote! {
+//!     fn test(b: bool) {
+//!         if b {
+//!             forward_goto!('skip);
+//!         }
+//!         println!("not skipped");
+//!         forward_label!('skip);
+//!     }
+//! };
+//!
+//! let report = forward_goto_core::rewrite_fn(&mut item).unwrap();
+//! assert_eq!(report.wraps().len(), 1);
+//! ```
+
+mod result;
+mod cfg;
+mod cleanup;
+mod collector;
+mod coverage;
+mod dynamic;
+mod error_labels;
+mod forms;
+mod graph;
+mod labels;
+mod optimize;
+mod options;
+mod report;
+mod scope;
+mod spans;
+mod trace;
+mod wrapper;
+
+pub use graph::{Edge, EdgeKind, Graph, Node, NodeKind};
+pub use options::Options;
+pub use report::{Report, Wrap};
+pub use result::Error;
+
+use collector::{Collector, Wrapper};
+use forms::{GotoIf, GotoIfLet, GotoMatch};
+use labels::LabelNames;
+use scope::ScopeChecker;
+use syn::*;
+use syn::visit_mut::{self, VisitMut};
+use syn::spanned::Spanned;
+use proc_macro2::Span;
+use result::{Result};
+use std::collections::VecDeque;
+
+/// Rewrites the gotos and labels in `item` with the default options.
+///
+/// See [`rewrite_fn_with_options`].
+pub fn rewrite_fn(item: &mut ItemFn) -> std::result::Result<Report, Vec<Error>> {
+    rewrite_fn_with_options(item, &Options::default())
+}
+
+/// Rewrites the gotos and labels in `item`, so that every goto jumps directly to its label.
+///
+/// On success, the returned [`Report`] describes which statements were wrapped for each label.
+/// On failure, all errors are returned and `item` is left in an unspecified state.
+pub fn rewrite_fn_with_options(item: &mut ItemFn, options: &Options) -> std::result::Result<Report, Vec<Error>> {
+    let sig = &item.sig;
+    wrapper::with_user_body(&mut item.block, &sig.output, |body, output| rewrite_body(sig, output, body, options))
+        .unwrap_or_else(|error| Err(vec![error]))
+}
+
+/// Rewrites the statements of the user, which might have been moved into a wrapper by another macro
+/// that returns `output`.
+fn rewrite_body(sig: &Signature, output: &ReturnType, body: &mut Block, options: &Options) -> std::result::Result<Report, Vec<Error>> {
+    if let Some(label) = &options.cleanup {
+        cleanup::lower(body, output, label).map_err(|errors| errors.into_iter().map(Error::from).collect::<Vec<_>>())?;
+    }
+    let mut names = LabelNames::new(sig, body);
+    forms::lower_handlers(body, &mut names).map_err(|err| vec![Error::from(err)])?;
+    error_labels::lower(body)
+        .map_err(|errors| errors.into_iter().map(Error::from).collect::<Vec<_>>())?;
+    dynamic::lower(body)
+        .map_err(|errors| errors.into_iter().map(Error::from).collect::<Vec<_>>())?;
+    cfg::check(body)
+        .map_err(|errors| errors.into_iter().map(Error::from).collect::<Vec<_>>())?;
+    if options.trace {
+        trace::instrument_labels(body, &options.crate_path(), &sig.ident, &names);
+    }
+    let hoisting = ScopeChecker::check(body, options.hoist)?;
+    forms::carry_handler_bindings(body, &hoisting.carried);
+
+    let mut collector = Collector::new(names, hoisting, Report::new(options.debug));
+    if options.trace {
+        collector.trace(options.crate_path(), sig.ident.clone());
+    }
+    // a const fn cannot register the table or count at runtime
+    if options.coverage && sig.constness.is_none() {
+        collector.count_gotos(options.crate_path());
+    }
+    let result = traverse_block(body, &mut collector);
+    let report = collector.take_report();
+    let generated = collector.take_generated_labels();
+    let edges = collector.take_coverage();
+    result
+        .and(collector.check())
+        .map_err(|err| vec![Error::from(err)])?;
+
+    optimize::flatten(body, generated);
+    if let Some(edges) = edges {
+        edges.declare(&sig.ident, body);
+    }
+    Ok(report)
+}
+
+fn traverse_block(block: &mut Block, collector: &mut Collector) -> Result<()> {
+    traverse_stmts(&mut block.stmts, collector)
+}
+
+/// Traverses the statements of a block and wraps them where necessary.
+///
+/// The statements are moved one by one from the input into `stmts`.
+/// A wrapper replaces the statements at the end of `stmts` that it encloses,
+/// so every statement is only moved a constant number of times per block.
+fn traverse_stmts(stmts: &mut Vec<Stmt>, collector: &mut Collector) -> Result<()> {
+    let mut input = std::mem::take(stmts).into_iter();
+    // a new wrapper has to be traversed again, because of the lifted continuations inside of it
+    let mut wrapper = None;
+    // statements that were generated for the previous statement and have not been traversed yet
+    let mut pending = VecDeque::new();
+
+    while let Some(mut stmt) = wrapper.take().or_else(|| pending.pop_front()).or_else(|| input.next()) {
+        let i = stmts.len();
+
+        pending.extend(forms::lower_fallthrough(&mut stmt, |span| collector.fallthrough_label(span))?);
+
+        if let Some(mac) = forms::goto_if_let_macro(&stmt) {
+            stmt = goto_if_let_local(mac)?;
+        }
+
+        if let Stmt::Local(local) = &mut stmt {
+            let id = scope::take_local_id(local);
+            if id.is_some_and(|id| collector.is_moved_mut(id)) {
+                local.attrs.push(parse_quote!(#[allow(unused_mut)]));
+            }
+            if id.is_some_and(|id| collector.is_hoisted(id)) {
+                let (decls, assignment) = hoist_local(local.clone());
+                {
+                    let mut collector = collector.enter_statement(i);
+                    for decl in decls {
+                        collector.add_hoisted(decl);
+                    }
+                }
+
+                match assignment {
+                    Some(assignment) => stmt = assignment,
+                    None => continue,
+                }
+            }
+        }
+
+        stmts.push(stmt);
+        {
+            let mut collector = collector.enter_statement(i);
+            traverse_stmt(&mut stmts[i], &mut collector)?;
+        }
+
+        if let Some(new_wrapper) = collector.retrieve_continuations() {
+            let mut inner = stmts.split_off(new_wrapper.start_index);
+            collector.report_wrap(&new_wrapper, &inner);
+
+            let Wrapper { start_index, end_label, continuations, .. } = new_wrapper;
+            let hoisted = collector.take_hoisted(start_index);
+            collector.skip_hoisted(start_index, hoisted.len());
+            push_break_stmt(&mut inner, end_label.clone());
+
+            // the loop directly around `inner` does not need to be traversed again
+            let innermost = continuations
+                .first()
+                .map_or(&end_label, |(incomings, _, _)| &incomings[0]);
+            collector.mark_traversed(innermost.clone());
+
+            for (incomings, continuation, outgoing) in continuations {
+                inner = {
+                    let mut inside_stmts = inner;
+
+                    if let Some(last) = incomings.last().cloned() {
+                        for incoming in incomings.into_iter() {
+                            push_break_stmt(&mut inside_stmts, last.clone());
+                            inside_stmts = vec![new_loop_block(incoming, inside_stmts)];
+                        }
+                    }
+
+                    inside_stmts.extend(continuation);
+                    push_break_stmt(&mut inside_stmts, outgoing);
+                    inside_stmts
+                }
+            }
+            stmts.extend(hoisted);
+            wrapper = Some(new_loop_block(end_label, inner));
+            continue;
+        }
+
+        if collector.should_push_continuation() {
+            let continuation: Vec<_> = pending.drain(..).chain(input).collect();
+            if let Some(stmt@Stmt::Expr(_)) = continuation.last() {
+                collector.add_error(stmt, "Result statement is in label continuation and cannot result in a value. Consider adding ';'");
+            }
+            let target = collector.push_continuation(continuation);
+            push_break_stmt(stmts, target);
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+fn traverse_stmt(stmt: &mut Stmt, collector: &mut Collector) -> Result<()> {
+    match stmt {
+        Stmt::Item(Item::Macro(ItemMacro { ident: None, mac, .. })) => {
+            // a statement macro with braces, e.g. `check! { x > 0, 'fail }`
+            let span = mac.span();
+            collector.add_macro_gotos(&mut mac.tokens, span)
+        },
+        Stmt::Item(_) => Ok(()),
+        Stmt::Local(local) => {
+            match local.init {
+                Some((_, ref mut expr_box)) => 
+                    traverse_boxed_expr(expr_box, collector),
+                None => Ok(()),
+            }
+        },
+        Stmt::Expr(expr) => traverse_expr(expr, collector, true),
+        Stmt::Semi(expr, _) => traverse_expr(expr, collector, true),
+    }
+}
+
+/// Turns `forward_goto_if_let!(pat = expr, 'label);` into a `let` statement
+/// that binds the pattern or jumps to the label.
+fn goto_if_let_local(mac: &ExprMacro) -> Result<Stmt> {
+    let span = mac.span();
+    let mut args: GotoIfLet = mac.mac.parse_body().map_err(|err| (err.span(), err.to_string()))?;
+
+    let mut bindings = Vec::new();
+    ImmutableBindings { bindings: &mut bindings, refutable: true }.visit_pat_mut(&mut args.pat);
+    Ok(args.into_local(&bindings, mac.attrs.clone(), span))
+}
+
+/// Splits `let pat = init;` into declarations of all bindings in `pat`
+/// and an assignment of `init` to them.
+fn hoist_local(local: Local) -> (Vec<Stmt>, Option<Stmt>) {
+    match local.pat {
+        Pat::Ident(PatIdent { ident, mutability, subpat: None, by_ref: None, .. }) => {
+            let decl = parse_quote!(let #mutability #ident;);
+            let assignment = local.init.map(|(_, init)| parse_quote!(#ident = #init;));
+            (vec![decl], assignment)
+        },
+        Pat::Type(PatType { pat, ty, .. }) if matches!(&*pat, Pat::Ident(PatIdent { subpat: None, by_ref: None, .. })) => {
+            let (ident, mutability) = match *pat {
+                Pat::Ident(PatIdent { ident, mutability, .. }) => (ident, mutability),
+                _ => unreachable!(),
+            };
+            let decl = parse_quote!(let #mutability #ident: #ty;);
+            let assignment = local.init.map(|(_, init)| parse_quote!(#ident = #init;));
+            (vec![decl], assignment)
+        },
+        mut pat => {
+            // destructure into fresh bindings first and then assign them all at once
+            let mut bindings = Vec::new();
+            ImmutableBindings { bindings: &mut bindings, refutable: false }.visit_pat_mut(&mut pat);
+            let decls = bindings
+                .iter()
+                .map(|(ident, mutability)| parse_quote!(let #mutability #ident;))
+                .collect();
+            let idents: Vec<_> = bindings.iter().map(|(ident, _)| ident).collect();
+            let assignment = local.init.map(|(_, init)| parse_quote!(
+                (#(#idents,)*) = { let #pat = #init; (#(#idents,)*) };
+            ));
+            (decls, assignment)
+        },
+    }
+}
+
+/// Collects all bindings of a pattern and makes them immutable.
+///
+/// The `mut` of a `ref mut` binding belongs to the reference, so it stays in the pattern.
+struct ImmutableBindings<'b> {
+    bindings: &'b mut Vec<(Ident, Option<Token![mut]>)>,
+    /// Whether the pattern is refutable, see [`scope::is_binding`].
+    refutable: bool,
+}
+
+impl<'b> VisitMut for ImmutableBindings<'b> {
+    fn visit_pat_ident_mut(&mut self, pat_ident: &mut PatIdent) {
+        if scope::is_binding(pat_ident, self.refutable) {
+            let mutability = match pat_ident.by_ref {
+                Some(_) => None,
+                None => pat_ident.mutability.take(),
+            };
+            self.bindings.push((pat_ident.ident.clone(), mutability));
+        }
+        visit_mut::visit_pat_ident_mut(self, pat_ident);
+    }
+}
+
+fn traverse_boxed_expr(expr: &mut Box<Expr>, collector: &mut Collector) -> Result<()> {
+    traverse_expr(expr, collector, false)
+}
+
+fn traverse_expr(expr: &mut Expr, collector: &mut Collector, is_statement: bool) -> Result<()> {
+    let replacement_expr = match expr {
+        Expr::Macro(mac) => {
+            let span = mac.span();
+            let mac_attrs = &mac.attrs;
+            let mac = &mac.mac;
+            let path = &mac.path;
+            if path.is_ident("forward_goto") || path.is_ident("forward_label") {
+                let tokens = &mac.tokens;
+                let user_lifetime: Lifetime = parse2(tokens.clone()).unwrap();
+                let lifetime = collector.user_label(&user_lifetime)?;
+
+                if path.is_ident("forward_goto") {
+                    collector.add_goto(lifetime.clone(), span);
+                    let mut replacement = new_break_expr(lifetime, span);
+                    let hits: Vec<_> = collector.trace_goto(&user_lifetime, span)
+                        .into_iter()
+                        .chain(collector.count_goto(&user_lifetime, span))
+                        .collect();
+                    if !hits.is_empty() {
+                        replacement = parse_quote_spanned!(span=> { #(#hits;)* #replacement });
+                    }
+                    match &mut replacement {
+                        Expr::Break(ExprBreak { attrs, .. }) | Expr::Block(ExprBlock { attrs, .. }) => {
+                            attrs.extend(mac_attrs.iter().cloned());
+                        },
+                        _ => unreachable!(),
+                    }
+                    Some(replacement)
+                } else {
+                    collector.add_label(lifetime.clone(), span)?;
+
+                    // the label statement is replaced by a break that might follow
+                    // diverging user code (e.g. a `return` before an error label)
+                    let mut replacement = new_break_expr(lifetime, span);
+                    if let Expr::Break(ExprBreak { attrs, .. }) = &mut replacement {
+                        if is_statement {
+                            attrs.push(parse_quote!(#[allow(unreachable_code)]));
+                        }
+                        attrs.extend(mac_attrs.iter().filter(|attr| !forms::is_handler_marker(attr)).cloned());
+                    }
+                    Some(replacement)
+                }
+            } else if path.is_ident("forward_goto_if") {
+                let GotoIf { cond, label } = mac.parse_body().map_err(|err| (err.span(), err.to_string()))?;
+                let mut replacement = parse_quote_spanned!(span=> #(#mac_attrs)* if #cond { forward_goto!(#label); });
+                traverse_expr(&mut replacement, collector, is_statement)?;
+                Some(replacement)
+            } else if path.is_ident("forward_goto_match") {
+                let args: GotoMatch = mac.parse_body().map_err(|err| (err.span(), err.to_string()))?;
+                let mut replacement = args.into_match(span);
+                if let Expr::Match(ExprMatch { attrs, .. }) = &mut replacement {
+                    attrs.extend(mac_attrs.iter().cloned());
+                }
+                traverse_expr(&mut replacement, collector, is_statement)?;
+                Some(replacement)
+            } else if path.is_ident("fallthrough") {
+                return Err((span, "fallthrough!() can only be used at the end of an arm of a match statement".into()));
+            } else if path.is_ident("forward_goto_if_let") {
+                return Err((span, "forward_goto_if_let! can only be used as a statement".into()));
+            } else if path.is_ident("forward_handler") {
+                return Err((span, "forward_handler! can only be used as a statement".into()));
+            } else {
+                // other macros may expand to a goto to a label that is passed to them
+                let mut tokens = mac.tokens.clone();
+                collector.add_macro_gotos(&mut tokens, span)?;
+                if let Expr::Macro(mac) = expr {
+                    mac.mac.tokens = tokens;
+                }
+                None
+            }
+        },
+        Expr::If(ExprIf { cond, then_branch, else_branch, .. }) => {
+            traverse_boxed_expr(cond, &mut collector.cut())?;
+            traverse_block(then_branch, &mut collector.enter())?;
+            if let Some((_, expr)) = else_branch {
+                traverse_boxed_expr(expr, &mut collector.enter())?;
+            }
+            None
+        },
+        Expr::Match(ExprMatch { expr, arms, .. }) => {
+            traverse_boxed_expr(expr, &mut collector.cut())?;
+            for arm in arms.iter_mut() {
+                traverse_boxed_expr(&mut arm.body, &mut collector.enter())?;
+            }
+            None
+        },
+        Expr::Block(ExprBlock { block, label, ..}) => {
+            collector.with_loop_label(label.as_ref(), |collector| {
+                traverse_block(block, &mut collector.enter())
+            })?;
+            None
+        },
+        Expr::Assign(ExprAssign { right, .. }) => {
+            // hoisted `let` statements become assignments
+            traverse_boxed_expr(right, collector)?;
+            None
+        },
+        Expr::Paren(ExprParen { expr, .. }) => {
+            traverse_boxed_expr(expr, collector)?;
+            None
+        },
+        Expr::Let(ExprLet { expr, .. }) => {
+            traverse_boxed_expr(expr, &mut collector.cut())?;
+            None
+        },
+        Expr::Loop(ExprLoop { label: Some(label), .. }) if collector.take_traversed(&label.name) => None,
+        Expr::Loop(ExprLoop { body, label, .. }) => {
+            collector.with_loop_label(label.as_ref(), |collector| {
+                traverse_block(body, &mut collector.enter())
+            })?;
+            None
+        },
+        Expr::While(ExprWhile { cond, body, label, .. }) => {
+            traverse_boxed_expr(cond, &mut collector.cut())?;
+            collector.with_loop_label(label.as_ref(), |collector| {
+                traverse_block(body, &mut collector.enter())
+            })?;
+            None
+        },
+        Expr::ForLoop(ExprForLoop { expr, body, label, .. }) => {
+            traverse_boxed_expr(expr, &mut collector.cut())?;
+            collector.with_loop_label(label.as_ref(), |collector| {
+                traverse_block(body, &mut collector.enter())
+            })?;
+            None
+        },
+        // gotos cannot leave closures and async blocks, so their gotos are checked by rustc
+        Expr::Closure(_) | Expr::Async(_) => None,
+        Expr::Call(_) | Expr::MethodCall(_) | Expr::Binary(_) | Expr::Unary(_) | Expr::AssignOp(_)
+        | Expr::Tuple(_) | Expr::Array(_) | Expr::Repeat(_) | Expr::Struct(_) | Expr::Field(_)
+        | Expr::Index(_) | Expr::Range(_) | Expr::Reference(_) | Expr::Cast(_) | Expr::Type(_)
+        | Expr::Try(_) | Expr::Await(_) | Expr::Unsafe(_) | Expr::Group(_) | Expr::Break(_)
+        | Expr::Return(_) => {
+            // gotos in operands, e.g. in the arguments of a call, and the gotos of `return`
+            // and `?` that the cleanup option lowers
+            let mut children = Children { collector: &mut collector.cut(), result: Ok(()) };
+            visit_mut::visit_expr_mut(&mut children, expr);
+            children.result?;
+            None
+        },
+        _ => None,
+    };
+
+    if let Some(replacement) = replacement_expr {
+        *expr = replacement;
+    }
+
+    Ok(())
+}
+
+/// Traverses the direct child expressions and blocks of an expression.
+struct Children<'c> {
+    collector: &'c mut Collector,
+    result: Result<()>,
+}
+
+impl<'c> VisitMut for Children<'c> {
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if self.result.is_ok() {
+            self.result = traverse_expr(expr, self.collector, false);
+        }
+    }
+
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        if self.result.is_ok() {
+            self.result = traverse_block(block, &mut self.collector.enter());
+        }
+    }
+}
+
+/// Appends a generated `break 'lifetime;` to `stmts`.
+///
+/// Nothing is appended if `stmts` already ends with a labeled break,
+/// because the new break could never be reached.
+fn push_break_stmt(stmts: &mut Vec<Stmt>, lifetime: Lifetime) {
+    let ends_with_break = matches!(
+        stmts.last(),
+        Some(Stmt::Semi(Expr::Break(ExprBreak { label: Some(_), .. }), _))
+    );
+
+    if !ends_with_break {
+        stmts.push(new_break_stmt(lifetime));
+    }
+}
+
+/// Creates a generated `break 'lifetime;` statement.
+///
+/// The break itself might follow diverging user code,
+/// so `unreachable_code` is allowed for this statement only.
+///
+/// Generated labels carry the span of the goto or label they originate from,
+/// which is used for the break as well.
+fn new_break_stmt(lifetime: Lifetime) -> Stmt {
+    let span = lifetime.span();
+    let mut expr = new_break_expr(lifetime, span);
+    if let Expr::Break(ExprBreak { attrs, .. }) = &mut expr {
+        attrs.push(parse_quote!(#[allow(unreachable_code)]));
+    }
+    expr_to_stmt(expr, span)
+}
+
+fn expr_to_stmt(expr: Expr, span: Span) -> Stmt {
+    Stmt::Semi(expr, Token![;](span))
+}
+
+fn new_break_expr(lifetime: Lifetime, span: Span) -> Expr {
+    Expr::Break(ExprBreak {
+        attrs: Vec::new(),
+        break_token: Token![break](span),
+        label: Some(lifetime),
+        expr: None,
+    })
+}
+
+/// Creates a generated wrapper `'label: loop { body }`.
+///
+/// The loop never iterates, because every path through `body` ends in a break.
+/// The clippy lints complaining about that are allowed for the wrapper.
+/// The loop gets the span of the first statement it encloses.
+fn new_loop_block(label: Lifetime, body: Vec<Stmt>) -> Stmt {
+    let span = body.first().map_or_else(|| label.span(), spans::start_span);
+    expr_to_stmt(Expr::Loop(ExprLoop {
+        attrs: vec![parse_quote!(#[allow(clippy::never_loop, clippy::needless_continue)])],
+        label: Some(Label {
+            name: label,
+            colon_token: Token![:](span),
+        }),
+        loop_token: Token![loop](span),
+        body: Block {
+            brace_token: token::Brace { span },
+            stmts: body,
+        },
+    }), span)
+}
@@ -0,0 +1,184 @@
+use syn::*;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use std::collections::{HashMap, HashSet};
+use super::forms;
+
+/// Flattens the generated wrappers after the rewriting.
+///
+/// Every label adds another loop around the statements before it,
+/// so functions with many labels end up deeply nested. The pass removes
+/// the loops that are not needed to keep the control-flow the same:
+///
+/// 1. A generated break directly after a statement that always diverges is removed.
+/// 2. A loop `'a` whose body is only another loop `'b` followed by `break 'c` is replaced by
+///    the body of `'b`, in which every `break 'b` becomes `break 'c`.
+///    This merges the loops of labels at the same position and of empty continuations.
+/// 3. A loop that is only left by the break at its end is replaced by its body,
+///    as long as the body declares nothing that would become visible outside of it.
+///
+/// Only loops and breaks with labels in `generated` are touched.
+pub fn flatten(block: &mut Block, generated: HashSet<Ident>) {
+    let mut counter = BreakCounter {
+        generated: &generated,
+        counts: HashMap::new(),
+    };
+    counter.visit_block(block);
+
+    let mut flattener = Flattener {
+        counts: counter.counts,
+        generated,
+        renames: HashMap::new(),
+    };
+    flattener.visit_block_mut(block);
+
+    let mut renames = std::mem::take(&mut flattener.renames);
+    let labels: Vec<_> = renames.keys().cloned().collect();
+    for label in labels {
+        let target = resolve(&renames, &label);
+        renames.insert(label, target);
+    }
+    if !renames.is_empty() {
+        Renamer(&renames).visit_block_mut(block);
+    }
+}
+
+fn resolve(renames: &HashMap<Ident, Ident>, label: &Ident) -> Ident {
+    let mut label = label;
+    while let Some(target) = renames.get(label) {
+        label = target;
+    }
+    label.clone()
+}
+
+/// Counts the breaks to every generated label.
+struct BreakCounter<'g> {
+    generated: &'g HashSet<Ident>,
+    counts: HashMap<Ident, usize>,
+}
+
+impl<'g, 'ast> Visit<'ast> for BreakCounter<'g> {
+    fn visit_expr_break(&mut self, expr: &'ast ExprBreak) {
+        if let Some(label) = &expr.label {
+            if self.generated.contains(&label.ident) {
+                *self.counts.entry(label.ident.clone()).or_default() += 1;
+            }
+        }
+        visit::visit_expr_break(self, expr);
+    }
+
+    fn visit_item(&mut self, _: &'ast Item) {}
+}
+
+struct Flattener {
+    generated: HashSet<Ident>,
+    /// The number of breaks to every generated label, after renaming.
+    counts: HashMap<Ident, usize>,
+    /// Labels of merged loops and the labels their breaks go to now.
+    renames: HashMap<Ident, Ident>,
+}
+
+impl Flattener {
+    /// Returns the label of `stmt`, if it is a generated break.
+    fn break_label(&self, stmt: &Stmt) -> Option<Ident> {
+        match stmt {
+            Stmt::Semi(Expr::Break(ExprBreak { label: Some(label), expr: None, .. }), _)
+                if self.generated.contains(&label.ident) => Some(resolve(&self.renames, &label.ident)),
+            _ => None,
+        }
+    }
+
+    /// Removes the last statement of `stmts` if it is a generated break and returns its label.
+    fn pop_break(&mut self, stmts: &mut Vec<Stmt>) -> Option<Ident> {
+        let label = self.break_label(stmts.last()?)?;
+        stmts.pop();
+        *self.counts.get_mut(&label).unwrap() -= 1;
+        Some(label)
+    }
+
+    /// Flattens the body of the generated loop `label` and returns
+    /// the statements that replace the loop, if it is not needed anymore.
+    fn flatten_loop(&mut self, label: &Ident, body: &mut Vec<Stmt>) -> Option<Vec<Stmt>> {
+        if body.len() >= 2 && forms::diverges(&body[body.len() - 2]) {
+            self.pop_break(body);
+        }
+
+        if body.len() == 2 {
+            if let Some(target) = self.break_label(&body[1]) {
+                if let Stmt::Semi(Expr::Loop(ExprLoop { label: Some(inner), .. }), _) = &body[0] {
+                    if self.generated.contains(&inner.name.ident) {
+                        let inner = inner.name.ident.clone();
+                        self.pop_break(body);
+                        let inner_count = self.counts.remove(&inner).unwrap_or(0);
+                        *self.counts.entry(target.clone()).or_default() += inner_count;
+                        self.renames.insert(inner, target);
+
+                        *body = match body.pop() {
+                            Some(Stmt::Semi(Expr::Loop(inner_loop), _)) => inner_loop.body.stmts,
+                            _ => unreachable!(),
+                        };
+                    }
+                }
+            }
+        }
+
+        let only_left_at_end = self.counts.get(label) == Some(&1)
+            && self.break_label(body.last()?).as_ref() == Some(label);
+        if only_left_at_end && body.iter().all(is_scope_neutral) {
+            self.pop_break(body);
+            Some(std::mem::take(body))
+        } else {
+            None
+        }
+    }
+}
+
+impl VisitMut for Flattener {
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        let mut stmts = Vec::with_capacity(block.stmts.len());
+        for mut stmt in std::mem::take(&mut block.stmts) {
+            self.visit_stmt_mut(&mut stmt);
+
+            if let Stmt::Semi(Expr::Loop(ExprLoop { label: Some(label), body, .. }), _) = &mut stmt {
+                if self.generated.contains(&label.name.ident) {
+                    if let Some(replacement) = self.flatten_loop(&label.name.ident, &mut body.stmts) {
+                        stmts.extend(replacement);
+                        continue;
+                    }
+                }
+            }
+
+            stmts.push(stmt);
+        }
+        block.stmts = stmts;
+    }
+
+    fn visit_item_mut(&mut self, _: &mut Item) {}
+}
+
+/// Renames the labels of breaks to merged loops.
+struct Renamer<'r>(&'r HashMap<Ident, Ident>);
+
+impl<'r> VisitMut for Renamer<'r> {
+    fn visit_expr_break_mut(&mut self, expr: &mut ExprBreak) {
+        if let Some(label) = &mut expr.label {
+            if let Some(target) = self.0.get(&label.ident) {
+                label.ident = Ident::new(&target.to_string(), label.ident.span());
+            }
+        }
+        visit_mut::visit_expr_break_mut(self, expr);
+    }
+
+    fn visit_item_mut(&mut self, _: &mut Item) {}
+}
+
+/// Returns whether moving `stmt` into the enclosing block keeps all names resolving the same way.
+///
+/// Bindings and items would become visible to the statements after it.
+/// Macros are excluded as well, because they might expand to either.
+fn is_scope_neutral(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Local(_) | Stmt::Item(_) => false,
+        Stmt::Expr(expr) | Stmt::Semi(expr, _) => !matches!(expr, Expr::Macro(_)),
+    }
+}
@@ -0,0 +1,91 @@
+use syn::*;
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+
+/// Options given as arguments to `#[rewrite_forward_goto(...)]`.
+///
+/// New options may be added in the future, so create them with `Options::default()`.
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub struct Options {
+    /// Hoist variables declared between a goto and its label in front of the generated wrapper.
+    pub hoist: bool,
+    /// Print the rewritten function and a summary of the generated wrappers to stderr.
+    /// The engine itself never prints anything, but records the code of
+    /// the wrapped statements in the [`Report`](crate::Report).
+    pub debug: bool,
+    /// Route every `return` and `?` in front of this label through it.
+    /// The function returns the stored value at its end.
+    pub cleanup: Option<Lifetime>,
+    /// Report every goto and label that is passed at runtime to `forward_goto::trace::hit`.
+    pub trace: bool,
+    /// Count how often every goto is taken in a static table of `forward_goto::coverage`.
+    /// It is not an argument of the attribute, but enabled for all functions by the feature `coverage` of `forward_goto`.
+    /// The gotos of a `const fn` are not counted.
+    pub coverage: bool,
+    /// The path of the `forward_goto` crate in the code of the `trace` and `coverage` options,
+    /// `::forward_goto` if it is not set. Given as `crate = "path"`, e.g. if the crate is re-exported.
+    pub crate_path: Option<Path>,
+}
+
+impl Options {
+    /// Returns the path of the `forward_goto` crate in the generated code.
+    pub fn crate_path(&self) -> Path {
+        self.crate_path.clone().unwrap_or_else(|| parse_quote!(::forward_goto))
+    }
+}
+
+impl Parse for Options {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let mut options = Options::default();
+
+        for OptionArg { name: option, value } in Punctuated::<OptionArg, Token![,]>::parse_terminated(input)? {
+            match value {
+                None if option == "hoist" => options.hoist = true,
+                None if option == "debug" => options.debug = true,
+                None if option == "trace" => options.trace = true,
+                Some(OptionValue::Label(label)) if option == "cleanup" => options.cleanup = Some(label),
+                None if option == "cleanup" => {
+                    return Err(Error::new(option.span(), "The option `cleanup` needs a label, e.g. `cleanup = 'cleanup`"));
+                },
+                Some(OptionValue::Path(path)) if option == "crate" => options.crate_path = Some(path.parse()?),
+                None if option == "crate" => {
+                    return Err(Error::new(option.span(), "The option `crate` needs a path, e.g. `crate = \"::forward_goto\"`"));
+                },
+                _ => return Err(Error::new(option.span(), format!("Unknown option `{}`", option))),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// An option `name`, `name = 'label` or `name = "path"`.
+struct OptionArg {
+    name: Ident,
+    value: Option<OptionValue>,
+}
+
+enum OptionValue {
+    Label(Lifetime),
+    Path(LitStr),
+}
+
+impl Parse for OptionArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        // `crate` is a keyword
+        let name = Ident::parse_any(input)?;
+        let value = if input.peek(Token![=]) {
+            input.parse::<Token![=]>()?;
+            if input.peek(LitStr) {
+                Some(OptionValue::Path(input.parse()?))
+            } else {
+                Some(OptionValue::Label(input.parse()?))
+            }
+        } else {
+            None
+        };
+        Ok(Self { name, value })
+    }
+}
@@ -0,0 +1,150 @@
+use syn::*;
+use proc_macro2::Span;
+use std::fmt::{self, Display, Formatter};
+use super::collector::Continuation;
+use super::spans;
+
+/// A statement of the user that was wrapped or lifted.
+#[derive(Clone)]
+pub struct StmtInfo {
+    /// First and last source line, if the spans carry line information.
+    pub lines: Option<(usize, usize)>,
+    /// The first line of the rewritten statement. Only recorded with the `debug` option.
+    pub code: Option<String>,
+}
+
+impl StmtInfo {
+    fn new(stmt: &Stmt, with_code: bool) -> Self {
+        let code = if with_code {
+            // print the statement inside of a dummy function and take its first line
+            let file: File = parse_quote!(fn dummy() { #stmt });
+            let text = prettyplease::unparse(&file);
+            Some(text.lines().nth(1).unwrap_or_default().trim().to_owned())
+        } else {
+            None
+        };
+
+        Self {
+            lines: spans::lines(std::slice::from_ref(stmt)),
+            code,
+        }
+    }
+}
+
+/// A generated wrapper loop and the statements it encloses.
+#[derive(Clone)]
+pub struct Wrap {
+    /// The label of the outermost generated loop.
+    pub end_label: Lifetime,
+    /// The goto labels that are resolved by this wrapper, sorted by name.
+    pub labels: Vec<Lifetime>,
+    /// The statements enclosed by the loop, i.e. the statements that can be skipped by a goto.
+    pub wrapped: Vec<StmtInfo>,
+    /// Statements after labels that were lifted out of their blocks,
+    /// together with the labels they follow and the label they break to when they are done.
+    pub lifted: Vec<(Vec<Lifetime>, Vec<StmtInfo>, Lifetime)>,
+}
+
+/// Summary of all wrappers generated for a function.
+///
+/// Its `Display` implementation lists which statements
+/// were wrapped for each label, with their line numbers.
+///
+/// The wrappers are recorded before they are flattened, so loops of labels
+/// at the same position are listed separately even if they share a loop in the output.
+#[derive(Clone, Default)]
+pub struct Report {
+    with_code: bool,
+    wraps: Vec<Wrap>,
+    gotos: Vec<(Lifetime, Span)>,
+    labels: Vec<(Lifetime, Span)>,
+}
+
+impl Report {
+    /// Creates an empty report that records the code of
+    /// the wrapped statements if `with_code` is set.
+    pub(crate) fn new(with_code: bool) -> Self {
+        Self {
+            with_code,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn add_wrap(&mut self, labels: &[Lifetime], end_label: &Lifetime, wrapped: &[Stmt], continuations: &[Continuation]) {
+        let mut labels = labels.to_vec();
+        labels.sort_by_key(|label| label.to_string());
+
+        let with_code = self.with_code;
+        let infos = |stmts: &[Stmt]| stmts.iter().map(|stmt| StmtInfo::new(stmt, with_code)).collect();
+
+        self.wraps.push(Wrap {
+            end_label: end_label.clone(),
+            labels,
+            wrapped: infos(wrapped),
+            lifted: continuations
+                .iter()
+                .map(|(incomings, stmts, outgoing)| (incomings.clone(), infos(stmts), outgoing.clone()))
+                .collect(),
+        });
+    }
+
+    pub(crate) fn add_goto(&mut self, label: &Lifetime, span: Span) {
+        self.gotos.push((label.clone(), span));
+    }
+
+    pub(crate) fn add_label(&mut self, label: &Lifetime, span: Span) {
+        self.labels.push((label.clone(), span));
+    }
+
+    /// The generated wrappers in the order they were created.
+    pub fn wraps(&self) -> &[Wrap] {
+        &self.wraps
+    }
+
+    /// All gotos with the generated label they jump to.
+    pub fn gotos(&self) -> &[(Lifetime, Span)] {
+        &self.gotos
+    }
+
+    /// All goto labels with their generated name.
+    pub fn labels(&self) -> &[(Lifetime, Span)] {
+        &self.labels
+    }
+}
+
+fn join(labels: &[Lifetime]) -> String {
+    labels.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
+
+/// Writes the line and the first line of code of a statement.
+fn write_stmt(f: &mut Formatter<'_>, stmt: &StmtInfo) -> fmt::Result {
+    let code = stmt.code.as_deref().unwrap_or("...");
+    match stmt.lines {
+        Some((line, _)) => writeln!(f, "//     line {}: {}", line, code),
+        None => writeln!(f, "//     line ?: {}", code),
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.wraps.is_empty() {
+            return writeln!(f, "// no statements were wrapped");
+        }
+
+        for wrap in &self.wraps {
+            writeln!(f, "// loop {} (jump target of {}) wraps:", wrap.end_label, join(&wrap.labels))?;
+            for stmt in &wrap.wrapped {
+                write_stmt(f, stmt)?;
+            }
+
+            for (incomings, stmts, _) in wrap.lifted.iter().filter(|(_, stmts, _)| !stmts.is_empty()) {
+                writeln!(f, "//   lifted out after label {}:", join(incomings))?;
+                for stmt in stmts {
+                    write_stmt(f, stmt)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use syn::spanned::Spanned;
+
+pub type ErrInfo = (proc_macro2::Span, String);
+pub type Result<T> = std::result::Result<T, ErrInfo>;
+
+pub fn err(spanned: impl Spanned, msg: impl Into<String>) -> Result<()> {
+    Err((spanned.span(), msg.into()))
+}
+
+/// An error that prevents a function from being rewritten.
+#[derive(Debug, Clone)]
+pub struct Error {
+    pub span: proc_macro2::Span,
+    pub message: String,
+    /// Other places that explain the error, e.g. the goto and the label of an unavailable variable.
+    pub notes: Vec<(proc_macro2::Span, String)>,
+}
+
+impl Error {
+    pub fn new(span: proc_macro2::Span, message: impl Into<String>) -> Self {
+        Self { span, message: message.into(), notes: Vec::new() }
+    }
+
+    /// Adds a note at `span`.
+    pub fn with_note(mut self, span: proc_macro2::Span, note: impl Into<String>) -> Self {
+        self.notes.push((span, note.into()));
+        self
+    }
+
+    /// Creates a `compile_error!` invocation that reports this error at its span.
+    ///
+    /// A proc macro cannot attach notes to an error, so the notes are appended to the message,
+    /// with their lines if the spans carry line information.
+    pub fn to_compile_error(&self) -> proc_macro2::TokenStream {
+        let mut message = self.message.clone();
+        for (span, note) in &self.notes {
+            match crate::spans::line(*span) {
+                Some(line) => message.push_str(&format!("\nnote: {} (line {})", note, line)),
+                None => message.push_str(&format!("\nnote: {}", note)),
+            }
+        }
+        quote::quote_spanned!(self.span=> compile_error!(#message);)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ErrInfo> for Error {
+    fn from((span, message): ErrInfo) -> Self {
+        Self::new(span, message)
+    }
+}
@@ -0,0 +1,608 @@
+use syn::*;
+use syn::parse::Parser;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use std::collections::{HashMap, HashSet};
+use proc_macro2::{Span, TokenStream, TokenTree};
+use quote::ToTokens;
+use super::forms::{self, GotoIf, GotoIfLet, GotoMatch, GotoMatchArm};
+use super::labels;
+use super::result::Error;
+
+/// A goto/label pair that removes a binding from the scope after the label.
+struct Jump {
+    label: Lifetime,
+    /// The `seq` number at the start of the statement that will be wrapped first.
+    wrapper_seq: usize,
+    goto_span: Span,
+    label_span: Span,
+    /// For the label of a `forward_handler!`, the label behind its body, once it is reached.
+    /// The handler diverges, so the skipped bindings are still initialized behind it.
+    handler_end: Option<Lifetime>,
+}
+
+struct Goto {
+    seq: usize,
+    span: Span,
+    stmt_starts: Vec<usize>,
+}
+
+struct Binding {
+    ident: Ident,
+    mutable: bool,
+    seq: usize,
+    local: Option<usize>,
+    /// Index of the jump in [`ScopeChecker::jumps`] that made this binding unavailable.
+    unavailable: Option<usize>,
+    /// For unavailable bindings, the number of bindings in front of the next binding
+    /// that might still be available. Used to skip runs of unavailable bindings.
+    skip: usize,
+    reported: bool,
+}
+
+/// Resolves the names used in a function body before it gets rewritten.
+///
+/// Statements between a goto and its label are wrapped into a loop,
+/// so every binding declared in between goes out of scope at the label.
+/// Instead of letting rustc complain about the generated code,
+/// uses of such bindings after the label are reported here.
+///
+/// In hoisting mode, the `let` statements of such bindings are
+/// collected instead, so they can be declared in front of the wrapper.
+pub struct ScopeChecker {
+    next_seq: usize,
+    next_local: usize,
+    /// The depth of closures, async blocks and match guards. Their `let` statements are not stamped,
+    /// because the rewriting does not enter them and would leave the stamp behind.
+    opaque: usize,
+    hoist: bool,
+    /// All bindings in scope, the innermost last. Their `seq` numbers are ascending.
+    bindings: Vec<Binding>,
+    /// The number of bindings in front of each open scope.
+    scopes: Vec<usize>,
+    /// Indices of the bindings in scope for every name.
+    by_name: HashMap<Ident, Vec<usize>>,
+    jumps: Vec<Jump>,
+    gotos: HashMap<Lifetime, Goto>,
+    /// The `seq` numbers at the start of the current statement of every open block.
+    stmt_starts: Vec<usize>,
+    /// The names of all labels, which other macros may name as goto targets.
+    labels: HashSet<String>,
+    /// The `seq` numbers at which each name was used, ascending.
+    uses: HashMap<Ident, Vec<usize>>,
+    hoisted: HashSet<usize>,
+    carried: HashMap<Ident, Vec<(Ident, bool)>>,
+    moved_mut: HashSet<usize>,
+    errors: Vec<Error>,
+}
+
+/// The bindings that stay available after a label, because their scope is extended.
+///
+/// The `let` statements are identified by the id that [`ScopeChecker::check`] stamped on them,
+/// see [`take_local_id`].
+#[derive(Default)]
+pub struct Hoisting {
+    /// The ids of the `let` statements that are declared in front of the wrapper.
+    pub lets: HashSet<usize>,
+    /// The bindings that are carried over each handler, by the name of the label behind it.
+    /// The bool tells whether the binding is mutable.
+    pub carried: HashMap<Ident, Vec<(Ident, bool)>>,
+    /// The ids of the `let` statements of mutable carried bindings, which might not be mutated before they are moved.
+    pub moved_mut: HashSet<usize>,
+}
+
+impl ScopeChecker {
+    /// Checks the bindings in `block` and returns the bindings whose scope needs to be extended.
+    ///
+    /// Every `let` statement behind a goto whose label has not been reached yet is stamped with an id,
+    /// which has to be removed with [`take_local_id`].
+    pub fn check(block: &mut Block, hoist: bool) -> std::result::Result<Hoisting, Vec<Error>> {
+        let mut labels = HashSet::new();
+        labels::collect_label_names(quote::quote!(#block), &mut labels);
+        let mut checker = Self {
+            next_seq: 0,
+            next_local: 0,
+            opaque: 0,
+            hoist,
+            bindings: Vec::new(),
+            scopes: Vec::new(),
+            by_name: HashMap::new(),
+            jumps: Vec::new(),
+            gotos: HashMap::new(),
+            stmt_starts: Vec::new(),
+            labels,
+            uses: HashMap::new(),
+            hoisted: HashSet::new(),
+            carried: HashMap::new(),
+            moved_mut: HashSet::new(),
+            errors: Vec::new(),
+        };
+
+        checker.visit_block_mut(block);
+
+        if checker.errors.is_empty() {
+            Ok(Hoisting { lets: checker.hoisted, carried: checker.carried, moved_mut: checker.moved_mut })
+        } else {
+            Err(checker.errors)
+        }
+    }
+
+    fn next_seq(&mut self) -> usize {
+        self.next_seq += 1;
+        self.next_seq
+    }
+
+    /// Stamps a `let` statement with the next id and returns it, if its bindings might be hoisted or carried.
+    fn stamp(&mut self, attrs: &mut Vec<Attribute>) -> Option<usize> {
+        // the bindings can only become unavailable at the label of an earlier goto
+        if self.opaque > 0 || self.gotos.is_empty() {
+            return None;
+        }
+
+        let id = self.next_local;
+        self.next_local += 1;
+        let marker = Ident::new(LOCAL_MARKER, Span::call_site());
+        attrs.push(parse_quote!(#[#marker(#id)]));
+        Some(id)
+    }
+
+    fn opaque(&mut self, f: impl FnOnce(&mut Self)) {
+        self.opaque += 1;
+        f(self);
+        self.opaque -= 1;
+    }
+
+    /// Visits the arguments `args` that were parsed from the macro `tokens`,
+    /// and writes them back if a `let` statement in them was stamped.
+    fn visit_nested<T: ToTokens>(&mut self, tokens: &mut TokenStream, args: &mut T, f: impl FnOnce(&mut Self, &mut T)) {
+        let next_local = self.next_local;
+        f(self, args);
+        if self.next_local != next_local {
+            *tokens = args.to_token_stream();
+        }
+    }
+
+    fn declare(&mut self, pat: &Pat, refutable: bool, local: Option<usize>) {
+        assert!(!self.scopes.is_empty(), "bindings should be declared inside a scope");
+        let mut collector = PatBindings { idents: Vec::new(), refutable };
+        collector.visit_pat(pat);
+        let mut mutable = MutableBindings(Vec::new());
+        mutable.visit_pat(pat);
+
+        for ident in collector.idents {
+            let seq = self.next_seq();
+            self.by_name.entry(ident.clone()).or_default().push(self.bindings.len());
+            self.bindings.push(Binding {
+                mutable: mutable.0.contains(&ident),
+                ident,
+                seq,
+                local,
+                unavailable: None,
+                skip: 0,
+                reported: false,
+            });
+        }
+    }
+
+    fn with_scope(&mut self, pat: Option<&Pat>, f: impl FnOnce(&mut Self)) {
+        self.scopes.push(self.bindings.len());
+        if let Some(pat) = pat {
+            self.declare(pat, true, None);
+        }
+        f(self);
+
+        let start = self.scopes.pop().unwrap();
+        for binding in self.bindings.drain(start..).rev() {
+            if let Some(indices) = self.by_name.get_mut(&binding.ident) {
+                indices.pop();
+            }
+        }
+    }
+
+    fn add_goto(&mut self, label: Lifetime) {
+        let seq = self.next_seq();
+        let span = label.ident.span();
+        let stmt_starts = self.stmt_starts.clone();
+        self.gotos.entry(label).or_insert(Goto { seq, span, stmt_starts });
+    }
+
+    /// Returns the number of bindings up to and including the last binding
+    /// in front of `end` that is still available.
+    fn available_end(&mut self, end: usize) -> usize {
+        let mut available = end;
+        while available > 0 && self.bindings[available - 1].unavailable.is_some() {
+            available = self.bindings[available - 1].skip;
+        }
+
+        // compress the path, so the next search is faster
+        let mut current = end;
+        while current > available && self.bindings[current - 1].unavailable.is_some() {
+            let next = self.bindings[current - 1].skip;
+            self.bindings[current - 1].skip = available;
+            current = next;
+        }
+
+        available
+    }
+
+    /// Adds the label `label` and returns the index of its jump, if there are gotos to it.
+    fn add_label(&mut self, label: Lifetime) -> Option<usize> {
+        let Goto { seq: goto_seq, span: goto_span, stmt_starts } = self.gotos.remove(&label)?;
+        // the statement of the goto in the block of the label
+        let wrapper_seq = self.stmt_starts.len().checked_sub(1).and_then(|depth| stmt_starts.get(depth)).map_or(goto_seq, |&seq| seq);
+
+        let jump = self.jumps.len();
+        self.jumps.push(Jump {
+            label_span: label.ident.span(),
+            label,
+            wrapper_seq,
+            goto_span,
+            handler_end: None,
+        });
+
+        // the bindings declared after the goto are at the end and only the available ones are visited
+        let mut end = self.bindings.len();
+        loop {
+            end = self.available_end(end);
+            match self.bindings[..end].last_mut() {
+                Some(binding) if binding.seq > goto_seq => {
+                    binding.unavailable = Some(jump);
+                    binding.skip = end - 1;
+                },
+                _ => break,
+            }
+        }
+        Some(jump)
+    }
+
+    fn use_ident(&mut self, ident: &Ident) {
+        self.uses.entry(ident.clone()).or_default().push(self.next_seq);
+        let index = match self.by_name.get(ident).and_then(|indices| indices.last()) {
+            Some(index) => *index,
+            None => return,
+        };
+
+        let binding = &mut self.bindings[index];
+        let (jump, local, mutable, seq) = match (binding.unavailable, binding.reported) {
+            (Some(jump), false) => (jump, binding.local, binding.mutable, binding.seq),
+            _ => return,
+        };
+        binding.reported = true;
+
+        // a declaration in front of the wrapper would hide the other uses of the name in the wrapper,
+        // and the assignment that replaces the `let` could refer to another binding in the wrapper
+        let jump = &self.jumps[jump];
+        let uses = &self.uses[ident];
+        let first_use = uses.partition_point(|&use_seq| use_seq < jump.wrapper_seq);
+        let used_before = uses.get(first_use).is_some_and(|&use_seq| use_seq < seq);
+        let indices = &self.by_name[ident];
+        let shadows = indices.len() > 1 && self.bindings[indices[indices.len() - 2]].seq > jump.wrapper_seq;
+        let can_hoist = !used_before && !shadows;
+
+        match (&jump.handler_end, local) {
+            (Some(_), Some(local)) if can_hoist => {
+                self.hoisted.insert(local);
+                return;
+            },
+            (Some(end), _) => {
+                if let (Some(local), true) = (local, mutable) {
+                    self.moved_mut.insert(local);
+                }
+                self.carried.entry(end.ident.clone()).or_default().push((ident.clone(), mutable));
+                return;
+            },
+            (None, Some(local)) if self.hoist => {
+                if can_hoist {
+                    self.hoisted.insert(local);
+                } else {
+                    let label = &jump.label;
+                    self.errors.push(Error::new(ident.span(), format!(
+                        "`{}` cannot be declared in front of goto {}, because the name is also used for another binding after the goto. \
+                        Rename one of them",
+                        ident, label
+                    )));
+                }
+                return;
+            },
+            _ => (),
+        }
+
+        let label = &jump.label;
+        let error = Error::new(ident.span(), format!(
+            "`{}` is declared after goto {} and is not available after label {}",
+            ident, label, label
+        ));
+        self.errors.push(error
+            .with_note(jump.goto_span, format!("goto {} is here", label))
+            .with_note(jump.label_span, format!("label {} is here", label)));
+    }
+
+    fn use_tokens(&mut self, tokens: &TokenStream) {
+        for ident in macro_idents(tokens) {
+            self.use_ident(&ident);
+        }
+    }
+}
+
+/// Returns the identifiers in the arguments of a macro that might refer to local variables.
+///
+/// Arguments that parse as comma-separated expressions contribute the identifiers of their paths.
+/// Other arguments are scanned for identifiers that are not fields or the tail of a path.
+fn macro_idents(tokens: &TokenStream) -> Vec<Ident> {
+    match punctuated::Punctuated::<Expr, Token![,]>::parse_terminated.parse2(tokens.clone()) {
+        Ok(args) => {
+            let mut paths = PathIdents(Vec::new());
+            for arg in &args {
+                paths.visit_expr(arg);
+            }
+            paths.0
+        },
+        Err(_) => {
+            let mut idents = Vec::new();
+            scan_idents(tokens, &mut idents);
+            idents
+        },
+    }
+}
+
+fn scan_idents(tokens: &TokenStream, idents: &mut Vec<Ident>) {
+    let tokens: Vec<_> = tokens.clone().into_iter().collect();
+    let is_punct = |index: Option<usize>, ch: char| match index.and_then(|index| tokens.get(index)) {
+        Some(TokenTree::Punct(punct)) => punct.as_char() == ch,
+        _ => false,
+    };
+    for (index, token) in tokens.iter().enumerate() {
+        match token {
+            TokenTree::Ident(ident) => {
+                let before = index.checked_sub(1);
+                // `a.x` and `a::x` name a field and an item, `S { x: .. }` names a field
+                let is_tail = is_punct(before, '.') || (is_punct(before, ':') && is_punct(index.checked_sub(2), ':'));
+                let is_field = is_punct(Some(index + 1), ':') && !is_punct(Some(index + 2), ':');
+                if !is_tail && !is_field {
+                    idents.push(ident.clone());
+                }
+            },
+            TokenTree::Group(group) => scan_idents(&group.stream(), idents),
+            _ => (),
+        }
+    }
+}
+
+/// Collects the single identifiers of the paths in an expression, also in nested macros.
+struct PathIdents(Vec<Ident>);
+
+impl<'ast> Visit<'ast> for PathIdents {
+    fn visit_expr_path(&mut self, expr_path: &'ast ExprPath) {
+        if expr_path.qself.is_none() {
+            if let Some(ident) = expr_path.path.get_ident() {
+                self.0.push(ident.clone());
+            }
+        }
+        visit::visit_expr_path(self, expr_path);
+    }
+
+    fn visit_macro(&mut self, mac: &'ast Macro) {
+        self.0.extend(macro_idents(&mac.tokens));
+    }
+}
+
+impl VisitMut for ScopeChecker {
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        self.with_scope(None, |this| {
+            // the jump to the label of a handler, until the label behind its body is reached
+            let mut handler = None;
+            for stmt in &mut block.stmts {
+                let start = this.next_seq();
+                this.stmt_starts.push(start);
+                if let Some(label) = forms::handler_label(stmt) {
+                    handler = this.add_label(label);
+                } else {
+                    if let Some(end) = forms::stmt_label(stmt, "forward_label") {
+                        if let Some(jump) = handler.take() {
+                            this.jumps[jump].handler_end = Some(end);
+                        }
+                    }
+                    this.visit_stmt_mut(stmt);
+                }
+                this.stmt_starts.pop();
+            }
+        });
+    }
+
+    fn visit_item_mut(&mut self, _item: &mut Item) {
+        // items cannot see the local bindings of the function
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        match stmt {
+            Stmt::Semi(Expr::Macro(mac), _) if mac.mac.path.is_ident("forward_goto_if_let") => {
+                match mac.mac.parse_body::<GotoIfLet>() {
+                    Ok(mut args) => {
+                        self.visit_nested(&mut mac.mac.tokens, &mut args, |this, args| this.visit_expr_mut(&mut args.expr));
+                        self.add_goto(args.label);
+                        // the id goes to the `let` statement it is rewritten to
+                        let local = self.stamp(&mut mac.attrs);
+                        self.declare(&args.pat, true, local);
+                    },
+                    Err(_) => self.use_tokens(&mac.mac.tokens),
+                }
+            },
+            Stmt::Item(Item::Macro(ItemMacro { ident: None, mac, .. })) => self.visit_macro_mut(mac),
+            stmt => visit_mut::visit_stmt_mut(self, stmt),
+        }
+    }
+
+    fn visit_local_mut(&mut self, local: &mut Local) {
+        if let Some((_, init)) = &mut local.init {
+            self.visit_expr_mut(init);
+        }
+        let id = self.stamp(&mut local.attrs);
+        self.declare(&local.pat, false, id);
+    }
+
+    fn visit_arm_mut(&mut self, arm: &mut Arm) {
+        let Arm { pat, guard, body, .. } = arm;
+        self.with_scope(Some(pat), |this| {
+            if let Some((_, guard)) = guard {
+                this.opaque(|this| this.visit_expr_mut(guard));
+            }
+            this.visit_expr_mut(body);
+        });
+    }
+
+    fn visit_expr_if_mut(&mut self, expr_if: &mut ExprIf) {
+        let then_branch = &mut expr_if.then_branch;
+        match &mut *expr_if.cond {
+            Expr::Let(ExprLet { pat, expr, .. }) => {
+                self.visit_expr_mut(expr);
+                self.with_scope(Some(pat), |this| this.visit_block_mut(then_branch));
+            },
+            cond => {
+                self.visit_expr_mut(cond);
+                self.visit_block_mut(then_branch);
+            },
+        }
+
+        if let Some((_, else_branch)) = &mut expr_if.else_branch {
+            self.visit_expr_mut(else_branch);
+        }
+    }
+
+    fn visit_expr_while_mut(&mut self, expr_while: &mut ExprWhile) {
+        let body = &mut expr_while.body;
+        match &mut *expr_while.cond {
+            Expr::Let(ExprLet { pat, expr, .. }) => {
+                self.visit_expr_mut(expr);
+                self.with_scope(Some(pat), |this| this.visit_block_mut(body));
+            },
+            cond => {
+                self.visit_expr_mut(cond);
+                self.visit_block_mut(body);
+            },
+        }
+    }
+
+    fn visit_expr_for_loop_mut(&mut self, for_loop: &mut ExprForLoop) {
+        self.visit_expr_mut(&mut for_loop.expr);
+        let (pat, body) = (&for_loop.pat, &mut for_loop.body);
+        self.with_scope(None, |this| {
+            this.declare(pat, false, None);
+            this.visit_block_mut(body);
+        });
+    }
+
+    fn visit_expr_closure_mut(&mut self, closure: &mut ExprClosure) {
+        let ExprClosure { inputs, body, .. } = closure;
+        self.opaque(|this| this.with_scope(None, |this| {
+            for input in inputs.iter() {
+                this.declare(input, false, None);
+            }
+            this.visit_expr_mut(body);
+        }));
+    }
+
+    fn visit_expr_async_mut(&mut self, expr_async: &mut ExprAsync) {
+        self.opaque(|this| visit_mut::visit_expr_async_mut(this, expr_async));
+    }
+
+    fn visit_expr_path_mut(&mut self, expr_path: &mut ExprPath) {
+        if expr_path.qself.is_none() {
+            if let Some(ident) = expr_path.path.get_ident() {
+                self.use_ident(ident);
+            }
+        }
+        visit_mut::visit_expr_path_mut(self, expr_path);
+    }
+
+    fn visit_macro_mut(&mut self, mac: &mut Macro) {
+        let is_goto = mac.path.is_ident("forward_goto");
+        if is_goto || mac.path.is_ident("forward_label") {
+            if let Ok(label) = parse2::<Lifetime>(mac.tokens.clone()) {
+                if is_goto {
+                    self.add_goto(label);
+                } else {
+                    self.add_label(label);
+                }
+            }
+        } else if mac.path.is_ident("forward_goto_if") {
+            match mac.parse_body::<GotoIf>() {
+                Ok(mut args) => {
+                    self.visit_nested(&mut mac.tokens, &mut args, |this, args| this.visit_expr_mut(&mut args.cond));
+                    self.add_goto(args.label);
+                },
+                Err(_) => self.use_tokens(&mac.tokens),
+            }
+        } else if mac.path.is_ident("forward_goto_match") {
+            match mac.parse_body::<GotoMatch>() {
+                Ok(mut args) => {
+                    self.visit_nested(&mut mac.tokens, &mut args, |this, args| this.visit_expr_mut(&mut args.expr));
+                    for arm in args.arms {
+                        let GotoMatchArm { pat, mut guard, label } = arm;
+                        self.with_scope(Some(&pat), |this| {
+                            if let Some(guard) = &mut guard {
+                                this.opaque(|this| this.visit_expr_mut(guard));
+                            }
+                        });
+                        if let Some(label) = label {
+                            self.add_goto(label);
+                        }
+                    }
+                },
+                Err(_) => self.use_tokens(&mac.tokens),
+            }
+        } else {
+            self.use_tokens(&mac.tokens);
+            for label in labels::macro_labels(&mac.tokens, &self.labels) {
+                self.add_goto(label);
+            }
+        }
+    }
+}
+
+/// Collects the identifiers bound by a pattern.
+pub struct PatBindings {
+    pub idents: Vec<Ident>,
+    /// Whether the pattern is refutable, see [`is_binding`].
+    pub refutable: bool,
+}
+
+impl<'ast> Visit<'ast> for PatBindings {
+    fn visit_pat_ident(&mut self, pat_ident: &'ast PatIdent) {
+        if is_binding(pat_ident, self.refutable) {
+            self.idents.push(pat_ident.ident.clone());
+        }
+        visit::visit_pat_ident(self, pat_ident);
+    }
+}
+
+/// Collects the identifiers bound with `mut` by a pattern.
+struct MutableBindings(Vec<Ident>);
+
+impl<'ast> Visit<'ast> for MutableBindings {
+    fn visit_pat_ident(&mut self, pat_ident: &'ast PatIdent) {
+        if pat_ident.mutability.is_some() {
+            self.0.push(pat_ident.ident.clone());
+        }
+        visit::visit_pat_ident(self, pat_ident);
+    }
+}
+
+/// Returns whether an identifier pattern introduces a binding.
+///
+/// In refutable patterns, like the ones of `match` and `if let`, plain uppercase identifiers
+/// like `None` or `MAX` are most likely unit variants and constants and are not treated as bindings.
+/// In irrefutable patterns, like the ones of `let` statements, every identifier is a binding.
+pub fn is_binding(pat_ident: &PatIdent, refutable: bool) -> bool {
+    let is_plain = pat_ident.by_ref.is_none() && pat_ident.mutability.is_none() && pat_ident.subpat.is_none();
+    let looks_like_const = pat_ident.ident.to_string().starts_with(char::is_uppercase);
+    !(refutable && is_plain && looks_like_const)
+}
+
+const LOCAL_MARKER: &str = "forward_goto_local";
+
+/// Removes the id that [`ScopeChecker::check`] stamped on `local`, and returns it.
+pub fn take_local_id(local: &mut Local) -> Option<usize> {
+    let position = local.attrs.iter().position(|attr| attr.path.is_ident(LOCAL_MARKER))?;
+    let attr = local.attrs.remove(position);
+    attr.parse_args::<LitInt>().and_then(|id| id.base10_parse()).ok()
+}
@@ -0,0 +1,81 @@
+use syn::*;
+use syn::spanned::Spanned;
+use proc_macro2::Span;
+
+// `Spanned::span` converts the whole syntax tree into tokens, which is too slow
+// for statements that contain nested wrappers. The functions here only look
+// at the first or last token of the common statements and fall back to `span` otherwise.
+
+/// Returns the span of the first token of `stmt`, ignoring attributes.
+pub fn start_span(stmt: &Stmt) -> Span {
+    match stmt {
+        Stmt::Local(local) => local.let_token.span,
+        Stmt::Item(item) => item.span(),
+        Stmt::Expr(expr) | Stmt::Semi(expr, _) => expr_start_span(expr),
+    }
+}
+
+/// Returns the span of the last token of `stmt`.
+#[cfg(feature = "span-locations")]
+pub fn end_span(stmt: &Stmt) -> Span {
+    match stmt {
+        Stmt::Local(local) => local.semi_token.span,
+        Stmt::Item(item) => item.span(),
+        Stmt::Semi(_, semi) => semi.span,
+        Stmt::Expr(expr) => expr_end_span(expr),
+    }
+}
+
+fn expr_start_span(expr: &Expr) -> Span {
+    match expr {
+        Expr::Block(ExprBlock { label: Some(label), .. })
+        | Expr::Loop(ExprLoop { label: Some(label), .. }) => label.name.apostrophe,
+        Expr::Block(ExprBlock { block, .. }) => block.brace_token.span,
+        Expr::Loop(ExprLoop { loop_token, .. }) => loop_token.span,
+        Expr::If(ExprIf { if_token, .. }) => if_token.span,
+        Expr::Match(ExprMatch { match_token, .. }) => match_token.span,
+        Expr::Break(ExprBreak { break_token, .. }) => break_token.span,
+        Expr::Macro(ExprMacro { mac, .. }) => mac.path.span(),
+        expr => expr.span(),
+    }
+}
+
+#[cfg(feature = "span-locations")]
+fn expr_end_span(expr: &Expr) -> Span {
+    match expr {
+        Expr::If(ExprIf { else_branch: Some((_, else_branch)), .. }) => expr_end_span(else_branch),
+        Expr::If(ExprIf { then_branch: block, .. })
+        | Expr::Block(ExprBlock { block, .. })
+        | Expr::Loop(ExprLoop { body: block, .. }) => block.brace_token.span,
+        Expr::Match(ExprMatch { brace_token, .. }) => brace_token.span,
+        expr => expr.span(),
+    }
+}
+
+/// Returns the first and last line of `stmts`, if the spans carry line information.
+#[cfg(feature = "span-locations")]
+pub fn lines(stmts: &[Stmt]) -> Option<(usize, usize)> {
+    let start = start_span(stmts.first()?).start().line;
+    let end = end_span(stmts.last()?).end().line;
+    if start == 0 {
+        None
+    } else {
+        Some((start, end.max(start)))
+    }
+}
+
+#[cfg(not(feature = "span-locations"))]
+pub fn lines(_stmts: &[Stmt]) -> Option<(usize, usize)> {
+    None
+}
+
+/// Returns the line of `span`, if it carries line information.
+#[cfg(feature = "span-locations")]
+pub fn line(span: Span) -> Option<usize> {
+    Some(span.start().line).filter(|line| *line > 0)
+}
+
+#[cfg(not(feature = "span-locations"))]
+pub fn line(_span: Span) -> Option<usize> {
+    None
+}
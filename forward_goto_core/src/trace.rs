@@ -0,0 +1,76 @@
+use syn::*;
+use syn::spanned::Spanned;
+use syn::visit_mut::{self, VisitMut};
+use proc_macro2::Span;
+use super::labels::LabelNames;
+
+/// What is reported by a generated call to `forward_goto::trace::hit`.
+#[derive(Clone, Copy)]
+pub enum Event {
+    Goto,
+    Label,
+}
+
+/// Returns the call that reports that `label` was passed in `function`.
+///
+/// `label` is the label as written by the user, before it was renamed.
+/// The line is taken by `line!()` with the span of the goto or label.
+/// `krate` is the path of the `forward_goto` crate.
+pub fn hit(krate: &Path, function: &Ident, event: Event, label: &Lifetime, span: Span) -> Expr {
+    let function = function.to_string();
+    let label = label.to_string();
+    let event = match event {
+        Event::Goto => quote::quote_spanned!(span=> Goto),
+        Event::Label => quote::quote_spanned!(span=> Label),
+    };
+    parse_quote_spanned!(span=>
+        #krate::trace::hit(#function, #krate::trace::Event::#event, #label, ::core::line!())
+    )
+}
+
+/// Reports every label of the user, by inserting a call behind it.
+///
+/// The call is the first statement that runs after the label, no matter how it was reached.
+/// Gotos are reported where they are rewritten.
+pub fn instrument_labels(block: &mut Block, krate: &Path, function: &Ident, names: &LabelNames) {
+    struct Labels<'f> {
+        krate: &'f Path,
+        function: &'f Ident,
+        names: &'f LabelNames,
+    }
+
+    impl<'f> VisitMut for Labels<'f> {
+        fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+        fn visit_block_mut(&mut self, block: &mut Block) {
+            visit_mut::visit_block_mut(self, block);
+
+            let stmts = std::mem::take(&mut block.stmts);
+            for stmt in stmts {
+                let label = match &stmt {
+                    Stmt::Semi(Expr::Macro(mac), _) | Stmt::Expr(Expr::Macro(mac)) if mac.mac.path.is_ident("forward_label") => {
+                        parse2::<Lifetime>(mac.mac.tokens.clone())
+                            .ok()
+                            .filter(|label| !self.names.is_internal(label))
+                            .map(|label| (label, mac.mac.span()))
+                    },
+                    _ => None,
+                };
+                match label {
+                    Some((label, span)) => {
+                        let stmt = match stmt {
+                            Stmt::Expr(expr) => Stmt::Semi(expr, Token![;](span)),
+                            stmt => stmt,
+                        };
+                        block.stmts.push(stmt);
+                        let hit = hit(self.krate, self.function, Event::Label, &label, span);
+                        block.stmts.push(parse_quote_spanned!(span=> #hit;));
+                    },
+                    None => block.stmts.push(stmt),
+                }
+            }
+        }
+    }
+
+    Labels { krate, function, names }.visit_block_mut(block);
+}
@@ -0,0 +1,161 @@
+use syn::*;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+
+/// The macros that are rewritten, directly or by one of the lowering passes.
+const MACROS: &[&str] = &[
+    "forward_goto",
+    "forward_label",
+    "forward_goto_if",
+    "forward_goto_if_let",
+    "forward_goto_match",
+    "forward_handler",
+    "forward_try",
+    "goto_dyn",
+    "label_addr",
+    "fallthrough",
+];
+
+/// Calls `f` with the block that contains the statements of the user and its return type.
+///
+/// Attribute macros that run before `#[rewrite_forward_goto]` may move the body of the function
+/// into an async block or a closure, e.g. `#[tokio::main]`:
+///
+/// ```text
+/// fn main() { Runtime::new().unwrap().block_on(async { body }) }
+/// ```
+///
+/// If `block` uses no gotos outside of closures and async blocks, the one closure or async block
+/// that does is the body of the user. Wrappers can be nested. It is an error if more than one
+/// closure or async block uses gotos, because only the body of the user is rewritten.
+pub fn with_user_body<T>(
+    block: &mut Block,
+    output: &ReturnType,
+    f: impl FnOnce(&mut Block, &ReturnType) -> T,
+) -> std::result::Result<T, crate::Error> {
+    if uses_gotos(block, false) {
+        return Ok(f(block, output));
+    }
+
+    let mut counter = Counter { wrappers: Vec::new() };
+    counter.visit_block(block);
+    if let Some(&span) = counter.wrappers.get(1) {
+        return Err(crate::Error::new(
+            span,
+            "Gotos can only be used in the body of the function, or in the one closure or async block \
+            that an attribute moved the body into. Move the gotos of this closure into a function of its own",
+        )
+        .with_note(counter.wrappers[0], "the body of the function is assumed to be this closure"));
+    }
+
+    let mut finder = Finder { f: Some(f), output, result: None };
+    finder.visit_block_mut(block);
+    match finder {
+        Finder { result: Some(result), .. } => result,
+        Finder { f: Some(f), .. } => Ok(f(block, output)),
+        Finder { f: None, result: None, .. } => unreachable!(),
+    }
+}
+
+/// Returns whether `block` contains one of the rewritten macros,
+/// optionally also in closures and async blocks.
+fn uses_gotos(block: &Block, in_wrappers: bool) -> bool {
+    struct Uses {
+        in_wrappers: bool,
+        found: bool,
+    }
+
+    impl<'ast> Visit<'ast> for Uses {
+        fn visit_item(&mut self, _item: &'ast Item) {}
+
+        fn visit_expr_closure(&mut self, closure: &'ast ExprClosure) {
+            if self.in_wrappers {
+                visit::visit_expr_closure(self, closure);
+            }
+        }
+
+        fn visit_expr_async(&mut self, async_block: &'ast ExprAsync) {
+            if self.in_wrappers {
+                visit::visit_expr_async(self, async_block);
+            }
+        }
+
+        fn visit_macro(&mut self, mac: &'ast Macro) {
+            self.found |= MACROS.iter().any(|name| mac.path.is_ident(name));
+        }
+    }
+
+    let mut uses = Uses { in_wrappers, found: false };
+    uses.visit_block(block);
+    uses.found
+}
+
+/// Collects the spans of the outermost closures and async blocks with gotos.
+struct Counter {
+    wrappers: Vec<proc_macro2::Span>,
+}
+
+impl<'ast> Visit<'ast> for Counter {
+    fn visit_item(&mut self, _item: &'ast Item) {}
+
+    fn visit_expr(&mut self, expr: &'ast Expr) {
+        let block = match expr {
+            Expr::Closure(ExprClosure { body, .. }) => match &**body {
+                Expr::Block(ExprBlock { block, .. }) => Some(block),
+                _ => None,
+            },
+            Expr::Async(ExprAsync { block, .. }) => Some(block),
+            _ => None,
+        };
+        match block {
+            Some(block) if uses_gotos(block, true) => self.wrappers.push(expr.span()),
+            _ => visit::visit_expr(self, expr),
+        }
+    }
+}
+
+/// Finds the closure or async block with gotos and calls `f` with its body.
+struct Finder<'a, F, T> {
+    f: Option<F>,
+    output: &'a ReturnType,
+    result: Option<std::result::Result<T, crate::Error>>,
+}
+
+impl<F: FnOnce(&mut Block, &ReturnType) -> T, T> Finder<'_, F, T> {
+    fn wrapper(&mut self, block: &mut Block, output: Option<&ReturnType>) -> bool {
+        if !uses_gotos(block, true) {
+            return false;
+        }
+        if let Some(f) = self.f.take() {
+            // an async block returns the output of the function, as does a closure without a return type
+            let output = match output {
+                Some(output @ ReturnType::Type(..)) => output,
+                _ => self.output,
+            };
+            self.result = Some(with_user_body(block, output, f));
+        }
+        true
+    }
+}
+
+impl<F: FnOnce(&mut Block, &ReturnType) -> T, T> VisitMut for Finder<'_, F, T> {
+    fn visit_item_mut(&mut self, _item: &mut Item) {}
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if self.f.is_none() {
+            return;
+        }
+        let found = match expr {
+            Expr::Closure(ExprClosure { output, body, .. }) => match &mut **body {
+                Expr::Block(ExprBlock { block, .. }) => self.wrapper(block, Some(output)),
+                _ => false,
+            },
+            Expr::Async(ExprAsync { block, .. }) => self.wrapper(block, None),
+            _ => false,
+        };
+        if !found {
+            visit_mut::visit_expr_mut(self, expr);
+        }
+    }
+}
@@ -0,0 +1,705 @@
+use forward_goto_core::{rewrite_fn, rewrite_fn_with_options, Options};
+use quote::quote;
+use syn::{parse_quote, ItemFn};
+
+#[test]
+fn test_rewrite_fn() {
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool) {
+            if b {
+                forward_goto!('skip);
+            }
+            println!("not skipped");
+            forward_label!('skip);
+        }
+    };
+
+    let report = rewrite_fn(&mut item).unwrap();
+
+    let output = quote!(#item).to_string();
+    assert!(!output.contains("forward_goto"));
+    assert!(!output.contains("forward_label"));
+    assert!(output.contains("'skip : loop"));
+
+    assert_eq!(report.wraps().len(), 1);
+    let wrap = &report.wraps()[0];
+    assert_eq!(wrap.labels.len(), 1);
+    assert_eq!(wrap.labels[0].ident, "skip");
+    assert_eq!(wrap.wrapped.len(), 3);
+}
+
+#[test]
+fn test_rewrite_fn_errors() {
+    let mut item: ItemFn = parse_quote! {
+        fn test() {
+            forward_goto!('nowhere);
+        }
+    };
+
+    let errors = rewrite_fn(&mut item).err().unwrap();
+    let messages: Vec<_> = errors.iter().map(ToString::to_string).collect();
+    assert_eq!(messages, vec!["Could not find target label!"]);
+
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool) {
+            'outer: while b {
+                forward_goto!('outer);
+            }
+            forward_label!('outer);
+        }
+    };
+    let errors = rewrite_fn(&mut item).err().unwrap();
+    assert_eq!(errors[0].message, "Goto label 'outer has the same name as the enclosing loop label 'outer. Consider renaming one of them.");
+}
+
+#[test]
+fn test_fallthrough_errors() {
+    let message = |item: ItemFn| rewrite_fn(&mut item.clone()).err().unwrap()[0].message.clone();
+
+    assert_eq!(message(parse_quote! {
+        fn test(x: Option<u8>) {
+            match x {
+                None => fallthrough!(),
+                Some(y) => println!("{}", y),
+            }
+        }
+    }), "fallthrough!() cannot continue into an arm that binds `y`, because its pattern did not match");
+
+    assert_eq!(message(parse_quote! {
+        fn test(x: u8) {
+            match x {
+                0 => {},
+                _ => fallthrough!(),
+            }
+        }
+    }), "fallthrough!() cannot be used in the last match arm");
+
+    assert_eq!(message(parse_quote! {
+        fn test(x: u8) {
+            let y = match x {
+                0 => fallthrough!(),
+                _ => 1,
+            };
+        }
+    }), "fallthrough!() can only be used at the end of an arm of a match statement");
+}
+
+#[test]
+fn test_goto_dyn_errors() {
+    let message = |item: ItemFn| rewrite_fn(&mut item.clone()).err().unwrap()[0].message.clone();
+
+    assert_eq!(message(parse_quote! {
+        fn test() {
+            let target = label_addr!('missing);
+            goto_dyn!(target);
+        }
+    }), "label_addr! refers to the unknown label 'missing");
+
+    assert_eq!(message(parse_quote! {
+        fn test() {
+            let target = label_addr!('back);
+            forward_label!('back);
+            goto_dyn!(target);
+        }
+    }), "goto_dyn! can only jump forward, but label 'back taken by label_addr! comes before it");
+
+    assert_eq!(message(parse_quote! {
+        fn test(target: u8) {
+            goto_dyn!(target);
+        }
+    }), "goto_dyn! needs at least one label that is taken with label_addr!");
+}
+
+#[test]
+fn test_handler_errors() {
+    let message = |item: ItemFn| rewrite_fn(&mut item.clone()).err().unwrap()[0].message.clone();
+
+    assert_eq!(message(parse_quote! {
+        fn test(b: bool) -> i32 {
+            if b {
+                forward_goto!('fail);
+            }
+            let x = 5;
+            forward_handler!('fail, {
+                return x;
+            });
+            x
+        }
+    }), "`x` is declared after goto 'fail and is not available after label 'fail");
+
+    assert_eq!(message(parse_quote! {
+        fn test() {
+            let x = forward_handler!('fail, { return; });
+        }
+    }), "forward_handler! can only be used as a statement");
+}
+
+#[test]
+fn test_try_errors() {
+    let message = |item: ItemFn| rewrite_fn(&mut item.clone()).err().unwrap()[0].message.clone();
+
+    assert_eq!(message(parse_quote! {
+        fn test(input: &str) {
+            let n: u32 = forward_try!(input.parse(), 'fail);
+            forward_label!('fail);
+        }
+    }), "forward_try! needs a label that binds the error, like `forward_label!('fail, err: Error)`");
+
+    assert_eq!(message(parse_quote! {
+        fn test(b: bool) -> Result<(), E> {
+            if b {
+                forward_goto!('fail);
+            }
+            return Ok(());
+            forward_label!('fail, err: E);
+            Err(err)
+        }
+    }), "Label 'fail binds an error, so it can only be reached with forward_try!");
+
+    assert_eq!(message(parse_quote! {
+        fn test(input: &str) -> Result<u32, E> {
+            let n: u32 = forward_try!(input.parse(), 'fail);
+            println!("parsed {}", n);
+            forward_label!('fail, err: E);
+            Err(err)
+        }
+    }), "The statements in front of label 'fail reach it without an error. \
+        End them with `return`, or jump over the label");
+
+    assert_eq!(message(parse_quote! {
+        fn test(input: &str) -> Result<u32, E> {
+            forward_try!(input.parse::<u32>(), 'fail);
+            return Ok(1);
+            forward_label!('fail, err: ParseIntError);
+            forward_try!(input.parse::<bool>(), 'fail);
+            return Ok(2);
+            forward_label!('fail, err: ParseBoolError);
+            Err(err.into())
+        }
+    }), "Label 'fail binds errors of type `ParseIntError` and `ParseBoolError`. Rename one of the labels");
+}
+
+#[test]
+fn test_cfg() {
+    let item: ItemFn = parse_quote! {
+        fn test(b: bool) {
+            #[cfg(feature = "trace")]
+            forward_goto_if!(b, 'skip);
+            println!("not skipped");
+            forward_label!('skip);
+        }
+    };
+    assert!(expand(&item).contains("# [cfg (feature = \"trace\")] if b { break 'skip ; }"));
+
+    let message = |item: ItemFn| rewrite_fn(&mut item.clone()).err().unwrap()[0].message.clone();
+    let expected = "Label 'skip cannot be behind #[cfg], because the rewritten control flow cannot depend on the configuration. \
+        Put the #[cfg] on the statements next to the label instead";
+
+    assert_eq!(message(parse_quote! {
+        fn test(b: bool) {
+            forward_goto_if!(b, 'skip);
+            #[cfg(feature = "trace")]
+            forward_label!('skip);
+        }
+    }), expected);
+
+    assert_eq!(message(parse_quote! {
+        fn test(b: bool) -> i32 {
+            forward_goto_if!(b, 'skip);
+            #[cfg(feature = "trace")]
+            forward_handler!('skip, { return 0; });
+            1
+        }
+    }), expected);
+
+    assert_eq!(message(parse_quote! {
+        fn test(x: u8) {
+            match x {
+                0 => fallthrough!(),
+                #[cfg(feature = "trace")]
+                1 => println!("one"),
+                _ => {},
+            }
+        }
+    }), "fallthrough!() cannot continue into an arm with #[cfg]");
+}
+
+#[test]
+fn test_macro_gotos() {
+    let item: ItemFn = parse_quote! {
+        fn test<'fail>(x: &'fail u8) {
+            check!(*x > 0, 'fail);
+            println!("{}", x);
+            forward_label!('fail);
+        }
+    };
+
+    // the label is renamed in the arguments of the macro as well
+    let output = expand(&item);
+    assert!(output.contains("'fail_0 : loop { check ! (* x > 0 , 'fail_0) ;"));
+    assert!(output.contains("fn test < 'fail > (x : & 'fail u8)"));
+
+    // a lifetime in a type is not a goto, even if a label has the same name
+    let item: ItemFn = parse_quote! {
+        fn test<'a>(s: &'a str, b: bool) {
+            if b {
+                forward_goto!('a);
+            }
+            assert!(size_of::<&'a str>() == 16);
+            forward_label!('a);
+        }
+    };
+    let output = expand(&item);
+    assert!(output.contains("'a_0 : loop {"));
+    assert!(output.contains("assert ! (size_of :: < & 'a str > () == 16) ;"));
+
+    let message = |item: ItemFn| rewrite_fn(&mut item.clone()).err().unwrap()[0].message.clone();
+    assert_eq!(message(parse_quote! {
+        fn test(x: u8) {
+            forward_label!('fail);
+            check!(x > 0, 'fail);
+        }
+    }), "Found no goto to this label!");
+    assert_eq!(message(parse_quote! {
+        fn test(x: u8) {
+            check!(x > 0, &'fail);
+            forward_label!('fail);
+        }
+    }), "Found no goto to this label!");
+}
+
+#[test]
+fn test_wrappers() {
+    // only the one closure or async block that a wrapping attribute moved the body into is rewritten
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool) -> i32 {
+            let first = move || {
+                forward_goto_if!(b, 'skip);
+                println!("not skipped");
+                forward_label!('skip);
+            };
+            let second = async move {
+                forward_goto_if!(b, 'skip);
+                forward_label!('skip);
+            };
+            first();
+            drop(second);
+            0
+        }
+    };
+    let errors = rewrite_fn(&mut item).err().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "Gotos can only be used in the body of the function, or in the one closure or async block \
+        that an attribute moved the body into. Move the gotos of this closure into a function of its own");
+    assert_eq!(errors[0].notes[0].1, "the body of the function is assumed to be this closure");
+
+    // the cleanup label of a closure with a return type does not need the return value of the function
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool) -> i32 {
+            (move || -> () {
+                if b {
+                    return;
+                }
+                forward_label!('done);
+                println!("done");
+            })();
+            0
+        }
+    };
+    assert!(rewrite_fn_with_options(&mut item, &parse_quote!(cleanup = 'done)).is_ok());
+    let output = quote!(#item).to_string();
+    assert!(!output.contains("forward_label"));
+}
+
+#[test]
+fn test_rewrite_fn_with_options() {
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool) -> i32 {
+            if b {
+                forward_goto!('skip);
+            }
+            let x = 5;
+            forward_label!('skip);
+            x
+        }
+    };
+
+    let errors = rewrite_fn(&mut item.clone()).err().unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message, "`x` is declared after goto 'skip and is not available after label 'skip");
+    let notes: Vec<_> = errors[0].notes.iter().map(|(_, note)| note.as_str()).collect();
+    assert_eq!(notes, ["goto 'skip is here", "label 'skip is here"]);
+    let error = errors[0].to_compile_error().to_string();
+    assert_eq!(error.matches("compile_error").count(), 1);
+    // the lines of the notes depend on the span-locations feature
+    assert!(error.contains("not available after label 'skip\\nnote: goto 'skip is here"));
+    assert!(error.contains("\\nnote: label 'skip is here"));
+
+    // fields and the tails of paths in the arguments of macros are not uses of the variable
+    let mut fields: ItemFn = parse_quote! {
+        fn test(b: bool, p: Point) {
+            if b {
+                forward_goto!('skip);
+            }
+            let x = 5;
+            println!("{} {}", x, p.x);
+            forward_label!('skip);
+            println!("{} {:?}", p.x, Point { x: 1, y: p.y });
+            other!(p.x; Point { x: 1 }; module::x);
+        }
+    };
+    rewrite_fn(&mut fields).unwrap();
+
+    let mut options = Options::default();
+    options.hoist = true;
+    rewrite_fn_with_options(&mut item, &options).unwrap();
+    assert!(quote!(#item).to_string().starts_with("fn test (b : bool) -> i32 { let x ;"));
+
+    // the `let` statements are matched up by their ids, also after patterns and closures
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool, pair: (i32, i32)) -> i32 {
+            let f = |x: i32| { let y = x + 1; y };
+            forward_goto_if!(b, 'skip);
+            let g = |x: i32| { let y = x * 2; y };
+            let (a, mut c) = pair;
+            forward_label!('skip);
+            c = f(1) + g(2);
+            c
+        }
+    };
+    rewrite_fn_with_options(&mut item, &options).unwrap();
+    let output = quote!(#item).to_string();
+    assert!(output.starts_with("fn test (b : bool , pair : (i32 , i32)) -> i32 { let f = | x : i32 | { let y = x + 1 ; y } ; let g ; let a ; let mut c ;"));
+    assert!(output.contains("g = | x : i32 | { let y = x * 2 ; y } ; (a , c ,) = { let (a , c) = pair ; (a , c ,) } ;"));
+    assert!(!output.contains("forward_goto_local"));
+
+    let item: ItemFn = parse_quote! {
+        fn test(b: bool, x: i32) -> i32 {
+            forward_goto_if!(b, 'skip);
+            let x = x + 1;
+            forward_label!('skip);
+            x
+        }
+    };
+    let errors = rewrite_fn_with_options(&mut item.clone(), &options).err().unwrap();
+    assert_eq!(errors[0].message, "`x` cannot be declared in front of goto 'skip, because the name is also used for another binding after the goto. \
+        Rename one of them");
+
+    // an uppercase name is a binding in a `let` statement, but a constant in a `match` arm
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool, n: i32) -> i32 {
+            forward_goto_if!(b, 'skip);
+            let X = match n {
+                MAX => 0,
+                n => n,
+            };
+            forward_label!('skip);
+            X
+        }
+    };
+    let errors = rewrite_fn(&mut item.clone()).err().unwrap();
+    assert_eq!(errors[0].message, "`X` is declared after goto 'skip and is not available after label 'skip");
+    rewrite_fn_with_options(&mut item, &options).unwrap();
+    let output = quote!(#item).to_string();
+    assert!(output.starts_with("fn test (b : bool , n : i32) -> i32 { let X ;"));
+    assert!(!output.contains("let MAX"));
+}
+
+#[test]
+fn test_trace_option() {
+    let mut item: ItemFn = syn::parse_str("
+fn test(b: bool) {
+    if b {
+        forward_goto!('skip);
+    }
+    println!(\"not skipped\");
+    forward_label!('skip);
+}").unwrap();
+
+    rewrite_fn_with_options(&mut item, &parse_quote!(trace)).unwrap();
+    let output = quote!(#item).to_string();
+    assert!(output.contains(
+        "{ :: forward_goto :: trace :: hit (\"test\" , :: forward_goto :: trace :: Event :: Goto , \"'skip\" , :: core :: line ! ()) ; break 'skip }"
+    ));
+    assert!(output.contains(
+        "} ; :: forward_goto :: trace :: hit (\"test\" , :: forward_goto :: trace :: Event :: Label , \"'skip\" , :: core :: line ! ()) ; }"
+    ));
+
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool) {
+            forward_goto_if!(b, 'skip);
+            forward_label!('skip);
+        }
+    };
+    rewrite_fn_with_options(&mut item, &parse_quote!(trace, crate = "my::goto")).unwrap();
+    assert!(quote!(#item).to_string().contains(
+        "my :: goto :: trace :: hit (\"test\" , my :: goto :: trace :: Event :: Goto , \"'skip\" , :: core :: line ! ())"
+    ));
+
+    let error = syn::parse2::<Options>(quote!(crate)).err().unwrap();
+    assert_eq!(error.to_string(), "The option `crate` needs a path, e.g. `crate = \"::forward_goto\"`");
+    assert!(syn::parse2::<Options>(quote!(crate = "not a path")).is_err());
+}
+
+#[test]
+fn test_coverage_option() {
+    let mut item: ItemFn = parse_quote! {
+        fn test(b: bool) {
+            if b {
+                forward_goto!('skip);
+            }
+            println!("not skipped");
+            forward_label!('skip);
+        }
+    };
+
+    let mut options = Options::default();
+    options.coverage = true;
+    rewrite_fn_with_options(&mut item, &options).unwrap();
+    let output = quote!(#item).to_string();
+    assert!(output.starts_with(concat!(
+        "fn test (b : bool) { ",
+        "static __FORWARD_GOTO_EDGES : [:: forward_goto :: coverage :: Edge ; 1usize] = ",
+        "[:: forward_goto :: coverage :: Edge :: new (\"test\" , \"'skip\" , :: core :: file ! () , :: core :: line ! ())] ; ",
+        "static __FORWARD_GOTO_COVERAGE : :: forward_goto :: coverage :: Table = ",
+        ":: forward_goto :: coverage :: Table :: new (& __FORWARD_GOTO_EDGES) ; ",
+        "__FORWARD_GOTO_COVERAGE . register () ; ",
+    )));
+    assert!(output.contains("{ __FORWARD_GOTO_COVERAGE . hit (0usize) ; break 'skip }"));
+
+    let mut item: ItemFn = parse_quote! {
+        const fn test(b: bool) {
+            forward_goto_if!(b, 'skip);
+            forward_label!('skip);
+        }
+    };
+    rewrite_fn_with_options(&mut item, &options).unwrap();
+    assert!(!quote!(#item).to_string().contains("__FORWARD_GOTO_COVERAGE"));
+}
+
+#[test]
+fn test_cleanup_option() {
+    let options: Options = parse_quote!(hoist, cleanup = 'done);
+    assert!(options.hoist);
+    assert_eq!(options.cleanup.unwrap().to_string(), "'done");
+
+    let error = syn::parse2::<Options>(quote!(cleanup)).err().unwrap();
+    assert_eq!(error.to_string(), "The option `cleanup` needs a label, e.g. `cleanup = 'cleanup`");
+
+    let mut item: ItemFn = parse_quote! {
+        fn test() -> i32 {
+            return 1;
+        }
+    };
+    let errors = rewrite_fn_with_options(&mut item, &parse_quote!(cleanup = 'done)).err().unwrap();
+    assert_eq!(errors[0].message, "The cleanup option needs the statement `forward_label!('done);` in the body of the function");
+
+    let mut item: ItemFn = parse_quote! {
+        fn k(x: i32) -> i32 {
+            if x > 0 {
+                return x;
+            }
+            forward_label!('cleanup);
+            println!("cleanup");
+        }
+    };
+    let errors = rewrite_fn_with_options(&mut item, &parse_quote!(cleanup = 'cleanup)).err().unwrap();
+    assert_eq!(errors[0].message, "The statements in front of the cleanup label 'cleanup reach it without a return value. \
+        End them with `return`, or jump to the label");
+
+    let mut item: ItemFn = parse_quote! {
+        fn k(x: i32) {
+            if x > 0 {
+                return;
+            }
+            forward_label!('cleanup);
+            println!("cleanup");
+        }
+    };
+    assert!(rewrite_fn_with_options(&mut item, &parse_quote!(cleanup = 'cleanup)).is_ok());
+
+    let mut item: ItemFn = parse_quote! {
+        fn k(values: &[i32]) -> Opt<i32> {
+            let first = values.first()?;
+            return Some(*first);
+            forward_label!('cleanup);
+        }
+    };
+    let errors = rewrite_fn_with_options(&mut item, &parse_quote!(cleanup = 'cleanup)).err().unwrap();
+    assert_eq!(errors[0].message, "`?` in front of the cleanup label 'cleanup needs a function that returns `Option` or `Result`, \
+        written with that name");
+
+    let mut item: ItemFn = parse_quote! {
+        fn k(x: i32) {
+            if x > 0 {
+                return;
+            }
+            forward_label!('cleanup);
+            x + 1
+        }
+    };
+    let errors = rewrite_fn_with_options(&mut item, &parse_quote!(cleanup = 'cleanup)).err().unwrap();
+    assert_eq!(errors[0].message, "The statements behind the cleanup label 'cleanup cannot end with an expression, \
+        because the function returns the value that reached the label. End it with `;`");
+}
+
+#[test]
+fn test_debug_option() {
+    let mut item: ItemFn = syn::parse_str("
+fn test(b: bool) {
+    if b {
+        forward_goto!('skip);
+    }
+    println!(\"not skipped\");
+    forward_label!('skip);
+}").unwrap();
+
+    let report = rewrite_fn_with_options(&mut item, &parse_quote!(debug)).unwrap();
+    let lines = if cfg!(feature = "span-locations") { ["3", "6", "7"] } else { ["?", "?", "?"] };
+    assert_eq!(report.to_string(), format!(
+        concat!(
+            "// loop 'skip (jump target of 'skip) wraps:\n",
+            "//     line {}: if b {{\n",
+            "//     line {}: println!(\"not skipped\");\n",
+            "//     line {}: #[allow(unreachable_code)] break 'skip;\n",
+        ),
+        lines[0], lines[1], lines[2],
+    ));
+
+    // without the option, only the lines are recorded
+    let report = rewrite_fn(&mut syn::parse_str("fn test(b: bool) { forward_goto_if!(b, 'skip); forward_label!('skip); }").unwrap()).unwrap();
+    assert!(report.to_string().ends_with(": ...\n"));
+}
+
+// the graph carries the line numbers of the spans
+#[cfg(feature = "span-locations")]
+#[test]
+fn test_graph() {
+    let mut item: ItemFn = syn::parse_str("
+fn test(b: bool) {
+    if b {
+        forward_goto!('skip);
+    }
+    println!(\"not skipped\");
+    forward_label!('skip);
+}").unwrap();
+
+    let report = rewrite_fn(&mut item).unwrap();
+    let graph = forward_goto_core::Graph::new(&report);
+
+    assert_eq!(graph.to_json("test"), concat!(
+        "{\"function\": \"test\", \"nodes\": [",
+        "{\"id\": \"'skip\", \"kind\": \"label\", \"lines\": [7, 7]}, ",
+        "{\"id\": \"wrap0\", \"kind\": \"statements\", \"lines\": [3, 7]}, ",
+        "{\"id\": \"exit0\", \"kind\": \"exit\", \"lines\": null}], \"edges\": [",
+        "{\"from\": \"wrap0\", \"to\": \"exit0\", \"kind\": \"fallthrough\", \"line\": null}, ",
+        "{\"from\": \"wrap0\", \"to\": \"'skip\", \"kind\": \"fallthrough\", \"line\": null}, ",
+        "{\"from\": \"'skip\", \"to\": \"exit0\", \"kind\": \"fallthrough\", \"line\": null}, ",
+        "{\"from\": \"wrap0\", \"to\": \"'skip\", \"kind\": \"goto\", \"line\": 4}]}\n",
+    ));
+    assert!(graph.to_dot("test").contains("\"wrap0\" -> \"'skip\" [style=bold, label=\"goto (line 4)\"];"));
+}
+
+fn expand(item: &ItemFn) -> String {
+    let mut item = item.clone();
+    rewrite_fn(&mut item).unwrap();
+    quote!(#item).to_string()
+}
+
+#[test]
+fn test_deterministic_output() {
+    // many labels in the same block, resolved by a single wrapper
+    let gotos = (0..20).map(|i| {
+        let label = syn::Lifetime::new(&format!("'l{}", i), proc_macro2::Span::call_site());
+        quote!(if x == #i { forward_goto!(#label); })
+    });
+    let labels = (0..20).rev().map(|i| {
+        let label = syn::Lifetime::new(&format!("'l{}", i), proc_macro2::Span::call_site());
+        quote!(forward_label!(#label); x += 1;)
+    });
+    let item: ItemFn = parse_quote! {
+        fn test(mut x: usize) -> usize {
+            #(#gotos)*
+            {
+                x += 1;
+                #(#labels)*
+            }
+            x
+        }
+    };
+
+    let first = expand(&item);
+    for _ in 0..10 {
+        assert_eq!(expand(&item), first);
+    }
+}
+
+#[test]
+fn test_expansion() {
+    let item: ItemFn = parse_quote! {
+        fn test(b: bool) {
+            if b {
+                forward_goto!('a);
+                forward_goto!('b);
+            }
+            {
+                forward_label!('b);
+                println!("b");
+                forward_label!('a);
+                println!("a");
+            }
+        }
+    };
+
+    assert_eq!(expand(&item), concat!(
+        "fn test (b : bool) { ",
+        "# [allow (clippy :: never_loop , clippy :: needless_continue)] '_continuation1 : loop { ",
+        "# [allow (clippy :: never_loop , clippy :: needless_continue)] 'a : loop { ",
+        "# [allow (clippy :: never_loop , clippy :: needless_continue)] 'b : loop { ",
+        "if b { break 'a ; break 'b ; } ",
+        "{ # [allow (unreachable_code)] break 'b ; } ",
+        "} ; ",
+        "println ! (\"b\") ; # [allow (unreachable_code)] break 'a ; } ; ",
+        "println ! (\"a\") ; # [allow (unreachable_code)] break '_continuation1 ; } ; ",
+        "}",
+    ));
+}
+
+#[test]
+fn test_flat_expansion() {
+    // labels directly after each other share a single loop
+    let gotos = (0..100).map(|i| {
+        let label = syn::Lifetime::new(&format!("'l{}", i), proc_macro2::Span::call_site());
+        quote!(if x == #i { forward_goto!(#label); })
+    });
+    let labels = (0..100).map(|i| {
+        let label = syn::Lifetime::new(&format!("'l{}", i), proc_macro2::Span::call_site());
+        quote!(forward_label!(#label);)
+    });
+    let mut item: ItemFn = parse_quote! {
+        fn test(x: usize) {
+            #(#gotos)*
+            println!("not skipped");
+            #(#labels)*
+        }
+    };
+
+    let report = rewrite_fn(&mut item).unwrap();
+    assert_eq!(report.wraps().len(), 100);
+    assert_eq!(quote!(#item).to_string().matches(": loop {").count(), 1);
+}
+
+#[test]
+fn test_deterministic_errors() {
+    // the first goto without a label is reported
+    let gotos: String = (0..20).map(|i| format!("    forward_goto!('l{});\n", i)).collect();
+    let item: ItemFn = syn::parse_str(&format!("fn test() {{\n{}}}", gotos)).unwrap();
+
+    for _ in 0..10 {
+        let errors = rewrite_fn(&mut item.clone()).err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Could not find target label!");
+        #[cfg(feature = "span-locations")]
+        assert_eq!(errors[0].span.start().line, 2);
+    }
+}
@@ -0,0 +1,395 @@
+//! `forward-goto-expand` rewrites every `#[rewrite_forward_goto]` function
+//! in Rust source files into the goto-free code the attribute would generate.
+//!
+//! ```text
+//! forward-goto-expand [--check | --stdout | --graph-dot | --graph-json] <FILE>...
+//! ```
+//!
+//! By default the files are rewritten in place. With `--stdout` the result is printed instead,
+//! and with `--check` nothing is written, but the exit code is 1 if any file would change.
+//!
+//! `--graph-dot` and `--graph-json` print the control-flow graph between the gotos, labels and
+//! generated wrappers of every rewritten function instead, as Graphviz DOT or as one JSON object per line.
+//!
+//! The attribute and `use forward_goto::rewrite_forward_goto;` are removed, the import only
+//! if no attribute is left. Functions nested in rewritten functions are rewritten as well.
+//! Only the rewritten functions are reformatted, the rest of the file is kept as it is.
+//! Comments inside of rewritten functions are lost.
+
+use forward_goto_core::{rewrite_fn_with_options, Error, Graph, Options};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use syn::spanned::Spanned;
+use syn::visit::{self, Visit};
+use syn::visit_mut::{self, VisitMut};
+use syn::*;
+
+const USAGE: &str = "usage: forward-goto-expand [--check | --stdout | --graph-dot | --graph-json] <FILE>...";
+
+enum Mode {
+    InPlace,
+    Stdout,
+    Check,
+    GraphDot,
+    GraphJson,
+}
+
+struct Args {
+    mode: Mode,
+    files: Vec<PathBuf>,
+}
+
+fn parse_args() -> std::result::Result<Args, String> {
+    let mut mode = Mode::InPlace;
+    let mut files = Vec::new();
+
+    for arg in std::env::args_os().skip(1) {
+        match arg.to_str() {
+            Some("--check") => mode = Mode::Check,
+            Some("--stdout") => mode = Mode::Stdout,
+            Some("--graph-dot") => mode = Mode::GraphDot,
+            Some("--graph-json") => mode = Mode::GraphJson,
+            Some("-h") | Some("--help") => {
+                println!("{}", USAGE);
+                exit(0);
+            },
+            Some(flag) if flag.starts_with('-') => return Err(format!("unknown flag `{}`", flag)),
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+
+    if files.is_empty() {
+        return Err("no input files".into());
+    }
+
+    Ok(Args { mode, files })
+}
+
+fn main() {
+    let args = parse_args().unwrap_or_else(|err| {
+        eprintln!("error: {}\n{}", err, USAGE);
+        exit(2);
+    });
+
+    let mut failed = false;
+    for path in &args.files {
+        match expand_file(path) {
+            Ok(expansion) => match args.mode {
+                Mode::GraphDot => {
+                    for (name, graph) in expansion.graphs {
+                        print!("{}", graph.to_dot(&name));
+                    }
+                },
+                Mode::GraphJson => {
+                    for (name, graph) in expansion.graphs {
+                        print!("{}", graph.to_json(&name));
+                    }
+                },
+                _ => failed |= output(path, expansion.expanded, &args.mode),
+            },
+            Err(errors) => {
+                for err in errors {
+                    eprintln!("{}", err);
+                }
+                failed = true;
+            },
+        }
+    }
+
+    if failed {
+        exit(1);
+    }
+}
+
+/// Writes the rewritten content of the file at `path` according to `mode` and returns whether that failed.
+fn output(path: &Path, expanded: Option<String>, mode: &Mode) -> bool {
+    match expanded {
+        None => {
+            if let Mode::Stdout = mode {
+                print!("{}", std::fs::read_to_string(path).unwrap_or_default());
+            }
+            false
+        },
+        Some(expanded) => match mode {
+            Mode::InPlace => {
+                if let Err(err) = std::fs::write(path, expanded) {
+                    eprintln!("{}: error: could not write file: {}", path.display(), err);
+                    return true;
+                }
+                false
+            },
+            Mode::Check => {
+                eprintln!("{}: contains functions with gotos", path.display());
+                true
+            },
+            _ => {
+                print!("{}", expanded);
+                false
+            },
+        },
+    }
+}
+
+struct Expansion {
+    /// The new content of the file, or `None` if nothing had to be rewritten.
+    expanded: Option<String>,
+    /// The graphs of all rewritten functions with their names.
+    graphs: Vec<(String, Graph)>,
+}
+
+/// Rewrites the file at `path`.
+fn expand_file(path: &Path) -> std::result::Result<Expansion, Vec<String>> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|err| vec![format!("{}: error: could not read file: {}", path.display(), err)])?;
+
+    let file = parse_file(&source)
+        .map_err(|err| vec![format_error(path, err.span(), &err.to_string())])?;
+
+    let mut expander = Expander {
+        source: &source,
+        replacements: Vec::new(),
+        imports: Vec::new(),
+        graphs: Vec::new(),
+        errors: Vec::new(),
+    };
+    expander.visit_file(&file);
+
+    if !expander.errors.is_empty() {
+        return Err(expander.errors
+            .iter()
+            .flat_map(|err| {
+                let notes = err.notes.iter().map(|(span, note)| format_diagnostic(path, *span, "note", note));
+                std::iter::once(format_error(path, err.span, &err.message)).chain(notes)
+            })
+            .collect());
+    }
+
+    let graphs = expander.graphs;
+    if expander.replacements.is_empty() {
+        return Ok(Expansion { expanded: None, graphs });
+    }
+
+    let mut replacements = expander.replacements;
+    let kept = replace(&source, replacements.clone());
+    replacements.extend(expander.imports.into_iter().map(|range| (range, String::new())));
+    let expanded = replace(&source, replacements);
+    // the import is still needed by the attributes that were not rewritten, e.g. in other macros
+    if expanded.contains("rewrite_forward_goto") {
+        return Ok(Expansion { expanded: Some(kept), graphs });
+    }
+    Ok(Expansion { expanded: Some(expanded), graphs })
+}
+
+/// Replaces the ranges of `source` with their new text.
+fn replace(source: &str, mut replacements: Vec<(Range<usize>, String)>) -> String {
+    replacements.sort_by_key(|(range, _)| range.start);
+
+    let mut expanded = String::with_capacity(source.len());
+    let mut pos = 0;
+    for (range, text) in replacements {
+        expanded.push_str(&source[pos..range.start]);
+        expanded.push_str(&text);
+        pos = range.end;
+    }
+    expanded.push_str(&source[pos..]);
+    expanded
+}
+
+fn format_error(path: &Path, span: proc_macro2::Span, message: &str) -> String {
+    format_diagnostic(path, span, "error", message)
+}
+
+fn format_diagnostic(path: &Path, span: proc_macro2::Span, level: &str, message: &str) -> String {
+    let start = span.start();
+    format!("{}:{}:{}: {}: {}", path.display(), start.line, start.column + 1, level, message)
+}
+
+/// Collects the replacements for all rewritten functions of a file.
+struct Expander<'s> {
+    source: &'s str,
+    replacements: Vec<(Range<usize>, String)>,
+    /// The imports of the attribute, which are removed if no attribute is left.
+    imports: Vec<Range<usize>>,
+    graphs: Vec<(String, Graph)>,
+    errors: Vec<Error>,
+}
+
+impl<'s> Expander<'s> {
+    /// Returns the whitespace in front of the line that contains the byte `pos`.
+    fn indentation(&self, pos: usize) -> &'s str {
+        let line_start = self.source[..pos].rfind('\n').map_or(0, |i| i + 1);
+        let line = &self.source[line_start..];
+        &line[..line.len() - line.trim_start().len()]
+    }
+
+    /// Rewrites `item` and the functions nested in it if it has the attribute, and returns whether it did.
+    fn rewrite(&mut self, item: &mut ItemFn) -> bool {
+        let position = item.attrs.iter().position(is_rewrite_attr);
+        let attr = match position {
+            Some(position) => item.attrs.remove(position),
+            None => return false,
+        };
+
+        let options = if attr.tokens.is_empty() {
+            Ok(Options::default())
+        } else {
+            attr.parse_args::<Options>()
+        };
+        let options = match options {
+            Ok(options) => options,
+            Err(err) => {
+                self.errors.push(Error::new(err.span(), err.to_string()));
+                return true;
+            },
+        };
+
+        // the nested functions keep their attribute otherwise, because the rewriting skips items
+        Nested(self).visit_block_mut(&mut item.block);
+
+        match rewrite_fn_with_options(item, &options) {
+            Ok(report) => self.graphs.push((item.sig.ident.to_string(), Graph::new(&report))),
+            Err(errors) => self.errors.extend(errors),
+        }
+        true
+    }
+
+    /// Rewrites `item` if it has the attribute, replaces its `range` of the source,
+    /// and returns whether it did.
+    fn expand(&mut self, range: Range<usize>, mut item: ItemFn, prefix: &str) -> bool {
+        let errors = self.errors.len();
+        if !self.rewrite(&mut item) {
+            return false;
+        }
+
+        if self.errors.len() == errors {
+            let file = File {
+                shebang: None,
+                attrs: Vec::new(),
+                items: vec![Item::Fn(item)],
+            };
+            let indentation = self.indentation(range.start);
+            let text = prettyplease::unparse(&file)
+                .trim()
+                .lines()
+                .map(|line| if line.is_empty() { String::new() } else { format!("{}{}", indentation, line) })
+                .collect::<Vec<_>>()
+                .join("\n");
+            self.replacements.push((range, format!("{}{}", prefix, text.trim_start())));
+        }
+        true
+    }
+}
+
+/// Rewrites the functions and methods with the attribute in the body of a rewritten function.
+struct Nested<'e, 's>(&'e mut Expander<'s>);
+
+impl<'e, 's> VisitMut for Nested<'e, 's> {
+    fn visit_item_fn_mut(&mut self, item_fn: &mut ItemFn) {
+        if !self.0.rewrite(item_fn) {
+            visit_mut::visit_item_fn_mut(self, item_fn);
+        }
+    }
+
+    fn visit_impl_item_method_mut(&mut self, method: &mut ImplItemMethod) {
+        let mut item = ItemFn {
+            attrs: method.attrs.clone(),
+            vis: method.vis.clone(),
+            sig: method.sig.clone(),
+            block: Box::new(method.block.clone()),
+        };
+        if self.0.rewrite(&mut item) {
+            method.attrs = item.attrs;
+            method.sig = item.sig;
+            method.block = *item.block;
+        } else {
+            visit_mut::visit_impl_item_method_mut(self, method);
+        }
+    }
+
+    fn visit_trait_item_method_mut(&mut self, method: &mut TraitItemMethod) {
+        let block = match &method.default {
+            Some(block) => block.clone(),
+            None => return,
+        };
+        let mut item = ItemFn {
+            attrs: method.attrs.clone(),
+            vis: Visibility::Inherited,
+            sig: method.sig.clone(),
+            block: Box::new(block),
+        };
+        if self.0.rewrite(&mut item) {
+            method.attrs = item.attrs;
+            method.sig = item.sig;
+            method.default = Some(*item.block);
+        } else {
+            visit_mut::visit_trait_item_method_mut(self, method);
+        }
+    }
+}
+
+impl<'ast, 's> Visit<'ast> for Expander<'s> {
+    fn visit_item_fn(&mut self, item_fn: &'ast ItemFn) {
+        if !self.expand(item_fn.span().byte_range(), item_fn.clone(), "") {
+            visit::visit_item_fn(self, item_fn);
+        }
+    }
+
+    fn visit_impl_item_method(&mut self, method: &'ast ImplItemMethod) {
+        let item = ItemFn {
+            attrs: method.attrs.clone(),
+            vis: method.vis.clone(),
+            sig: method.sig.clone(),
+            block: Box::new(method.block.clone()),
+        };
+        let prefix = if method.defaultness.is_some() { "default " } else { "" };
+        if !self.expand(method.span().byte_range(), item, prefix) {
+            visit::visit_impl_item_method(self, method);
+        }
+    }
+
+    fn visit_trait_item_method(&mut self, method: &'ast TraitItemMethod) {
+        let block = match &method.default {
+            Some(block) => block.clone(),
+            None => return,
+        };
+        let item = ItemFn {
+            attrs: method.attrs.clone(),
+            vis: Visibility::Inherited,
+            sig: method.sig.clone(),
+            block: Box::new(block),
+        };
+        if !self.expand(method.span().byte_range(), item, "") {
+            visit::visit_trait_item_method(self, method);
+        }
+    }
+
+    fn visit_item_use(&mut self, item_use: &'ast ItemUse) {
+        if is_rewrite_import(&item_use.tree) {
+            // remove the whole line, if the import is the only thing on it
+            let range = item_use.span().byte_range();
+            let indentation = self.indentation(range.start);
+            let line_start = self.source[..range.start].rfind('\n').map_or(0, |i| i + 1);
+            let (start, end) = if line_start + indentation.len() == range.start && self.source[range.end..].starts_with('\n') {
+                (line_start, range.end + 1)
+            } else {
+                (range.start, range.end)
+            };
+            self.imports.push(start..end);
+        }
+    }
+}
+
+fn is_rewrite_attr(attr: &Attribute) -> bool {
+    attr.path.segments.last().is_some_and(|segment| segment.ident == "rewrite_forward_goto")
+}
+
+/// Returns whether `tree` is `forward_goto::rewrite_forward_goto`.
+fn is_rewrite_import(tree: &UseTree) -> bool {
+    match tree {
+        UseTree::Path(UsePath { ident, tree, .. }) if ident == "forward_goto" => {
+            matches!(&**tree, UseTree::Name(UseName { ident }) if ident == "rewrite_forward_goto")
+        },
+        _ => false,
+    }
+}
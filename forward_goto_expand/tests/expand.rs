@@ -0,0 +1,153 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+const SOURCE: &str = "\
+use forward_goto::rewrite_forward_goto;
+// kept
+#[rewrite_forward_goto]
+fn test(b: bool) {
+    if b {
+        forward_goto!('skip);
+    }
+    println!(\"not skipped\");
+    forward_label!('skip);
+}
+";
+
+fn write_input(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("forward_goto_expand_{}_{}.rs", name, std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+fn run(args: &[&str], path: &PathBuf) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_forward-goto-expand"))
+        .args(args)
+        .arg(path)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_stdout() {
+    let path = write_input("stdout", SOURCE);
+    let output = run(&["--stdout"], &path);
+
+    assert!(output.status.success());
+    let expanded = String::from_utf8(output.stdout).unwrap();
+    assert!(expanded.starts_with("// kept\nfn test(b: bool) {\n"));
+    assert!(expanded.contains("'skip: loop {"));
+    assert!(!expanded.contains("forward_goto"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), SOURCE);
+}
+
+#[test]
+fn test_in_place_and_check() {
+    let path = write_input("in_place", SOURCE);
+
+    assert_eq!(run(&["--check"], &path).status.code(), Some(1));
+
+    assert!(run(&[], &path).status.success());
+    assert_ne!(std::fs::read_to_string(&path).unwrap(), SOURCE);
+
+    assert!(run(&["--check"], &path).status.success());
+}
+
+#[test]
+fn test_nested_and_trait_methods() {
+    let source = "\
+use forward_goto::rewrite_forward_goto;
+trait Skip {
+    #[rewrite_forward_goto]
+    fn skip(&self, b: bool) {
+        forward_goto_if!(b, 'skip);
+        println!(\"not skipped\");
+        forward_label!('skip);
+    }
+}
+#[rewrite_forward_goto]
+fn outer(b: bool) {
+    #[rewrite_forward_goto]
+    fn inner(b: bool) {
+        forward_goto_if!(b, 'inner);
+        println!(\"inner\");
+        forward_label!('inner);
+    }
+    forward_goto_if!(b, 'outer);
+    inner(b);
+    forward_label!('outer);
+}
+";
+    let path = write_input("nested", source);
+    let output = run(&["--stdout"], &path);
+
+    assert!(output.status.success());
+    let expanded = String::from_utf8(output.stdout).unwrap();
+    assert!(expanded.starts_with("trait Skip {\n    fn skip(&self, b: bool) {\n"));
+    assert!(expanded.contains("'skip: loop {"));
+    assert!(expanded.contains("'inner: loop {"));
+    assert!(expanded.contains("'outer: loop {"));
+    assert!(!expanded.contains("forward_"));
+    assert!(!expanded.contains("rewrite_forward_goto"));
+
+    // the import stays as long as an attribute is left
+    let source = "\
+use forward_goto::rewrite_forward_goto;
+#[rewrite_forward_goto]
+fn test(b: bool) {
+    forward_goto_if!(b, 'skip);
+    forward_label!('skip);
+}
+macro_rules! later {
+    () => {
+        #[rewrite_forward_goto]
+        fn later() {}
+    };
+}
+";
+    let path = write_input("kept_import", source);
+    let output = run(&["--stdout"], &path);
+
+    assert!(output.status.success());
+    let expanded = String::from_utf8(output.stdout).unwrap();
+    assert!(expanded.starts_with("use forward_goto::rewrite_forward_goto;\nfn test(b: bool) {\n"));
+}
+
+#[test]
+fn test_errors() {
+    let path = write_input("errors", "#[rewrite_forward_goto]\nfn test() {\n    forward_goto!('nowhere);\n}\n");
+    let output = run(&["--stdout"], &path);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, format!("{}:3:20: error: Could not find target label!\n", path.display()));
+
+    let path = write_input(
+        "notes",
+        "#[rewrite_forward_goto]\nfn test(b: bool) -> i32 {\n    forward_goto_if!(b, 'skip);\n    let x = 5;\n    forward_label!('skip);\n    x\n}\n",
+    );
+    let output = run(&["--stdout"], &path);
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, format!(
+        concat!(
+            "{0}:6:5: error: `x` is declared after goto 'skip and is not available after label 'skip\n",
+            "{0}:3:26: note: goto 'skip is here\n",
+            "{0}:5:21: note: label 'skip is here\n",
+        ),
+        path.display()
+    ));
+}
+
+#[test]
+fn test_graph_dot() {
+    let path = write_input("graph_dot", SOURCE);
+    let output = run(&["--graph-dot"], &path);
+
+    assert!(output.status.success());
+    let dot = String::from_utf8(output.stdout).unwrap();
+    assert!(dot.starts_with("digraph \"test\" {\n"));
+    assert!(dot.contains("\"wrap0\" -> \"'skip\" [style=bold, label=\"goto (line 6)\"];"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), SOURCE);
+}
@@ -0,0 +1,92 @@
+//! The procedural macros of the `forward_goto` crate.
+//!
+//! Use them through `forward_goto`, which documents them with tested examples
+//! and also contains the runtime support for the `trace` option.
+
+extern crate proc_macro;
+
+use forward_goto_core::{Error, Options, Report};
+use quote::quote;
+use syn::*;
+
+#[proc_macro_attribute]
+pub fn rewrite_forward_goto(attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut options = parse_macro_input!(attr as Options);
+    options.coverage = cfg!(feature = "coverage");
+    let mut input = parse_macro_input!(item as ItemFn);
+
+    let dump_dir = std::env::var_os("FORWARD_GOTO_DUMP");
+    let to_stderr = options.debug;
+    // the summary in the dump needs the code of the wrapped statements
+    options.debug |= dump_dir.is_some();
+
+    let result = forward_goto_core::rewrite_fn_with_options(&mut input, &options).and_then(|report| {
+        if options.debug {
+            dump(&input, &report, to_stderr, dump_dir).map_err(|err| vec![err])
+        } else {
+            Ok(())
+        }
+    });
+
+    match result {
+        Ok(()) => {
+            proc_macro::TokenStream::from(quote!(#input))
+        },
+        Err(errors) => {
+            let errors = errors.iter().map(Error::to_compile_error);
+
+            input.block = parse_quote!(
+                {
+                    #(#errors)*
+                }
+            );
+
+            proc_macro::TokenStream::from(quote!(#input))
+        },
+    }
+}
+
+#[proc_macro]
+pub fn forward_goto(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let label = parse_macro_input!(input as Lifetime);
+    // the label keeps its span, so it resolves where it was written
+    proc_macro::TokenStream::from(quote!(break #label))
+}
+
+#[proc_macro]
+pub fn forward_label(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let span = proc_macro2::TokenStream::from(input)
+        .into_iter()
+        .next()
+        .map_or_else(proc_macro2::Span::call_site, |token| token.span());
+    let error = Error::new(
+        span,
+        "forward_label! has to be written directly in a function with #[rewrite_forward_goto], \
+            it cannot be produced by another macro",
+    );
+    proc_macro::TokenStream::from(error.to_compile_error())
+}
+
+/// Writes the rewritten function and the summary of its wrappers
+/// to stderr and/or to `<dump_dir>/<function name>.rs`.
+fn dump(input: &ItemFn, report: &Report, to_stderr: bool, dump_dir: Option<std::ffi::OsString>) -> std::result::Result<(), Error> {
+    let file = File {
+        shebang: None,
+        attrs: Vec::new(),
+        items: vec![Item::Fn(input.clone())],
+    };
+    let text = format!("// rewritten by #[rewrite_forward_goto]\n{}\n{}", report, prettyplease::unparse(&file));
+
+    if to_stderr {
+        eprintln!("{}", text);
+    }
+
+    if let Some(dir) = dump_dir {
+        let path = std::path::Path::new(&dir).join(format!("{}.rs", input.sig.ident));
+        std::fs::create_dir_all(&dir)
+            .and_then(|()| std::fs::write(&path, text))
+            .map_err(|err| Error::new(input.sig.ident.span(), format!("Could not write {}: {}", path.display(), err)))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,52 @@
+//! Stand-ins for attribute macros that wrap the body of a function,
+//! used to test `#[rewrite_forward_goto]` next to them.
+
+extern crate proc_macro;
+
+use quote::quote;
+use syn::*;
+
+/// Runs the body in an `async move` block on a minimal executor, like `#[tokio::main]`.
+///
+/// The annotated function has to be synchronous, because the body must not await anything
+/// that is not ready immediately.
+#[proc_macro_attribute]
+pub fn wrap_async(_attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut item = parse_macro_input!(item as ItemFn);
+    let block = &item.block;
+    item.block = parse_quote!({
+        use ::std::future::Future as _;
+        use ::std::task::{RawWaker, RawWakerVTable, Waker};
+
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RawWaker::new(::std::ptr::null(), &VTABLE), |_| {}, |_| {}, |_| {});
+
+        let body = async move #block;
+        let mut body = ::std::boxed::Box::pin(body);
+        // a waker that does nothing, because the body is polled until it is ready
+        let waker = unsafe { Waker::from_raw(RawWaker::new(::std::ptr::null(), &VTABLE)) };
+        let mut context = ::std::task::Context::from_waker(&waker);
+        loop {
+            if let ::std::task::Poll::Ready(value) = body.as_mut().poll(&mut context) {
+                break value;
+            }
+        }
+    });
+    proc_macro::TokenStream::from(quote!(#item))
+}
+
+/// Calls the body as a closure inside of a span guard, like `#[tracing::instrument(err)]`.
+#[proc_macro_attribute]
+pub fn wrap_closure(_attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let mut item = parse_macro_input!(item as ItemFn);
+    let block = &item.block;
+    let name = item.sig.ident.to_string();
+    item.block = parse_quote!({
+        let __span = #name;
+        let __guard = &__span;
+        #[allow(clippy::redundant_closure_call)]
+        let value = (move || #block)();
+        let _ = __guard;
+        value
+    });
+    proc_macro::TokenStream::from(quote!(#item))
+}
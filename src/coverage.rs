@@ -0,0 +1,122 @@
+//! Counters for the gotos of all rewritten functions, enabled by the cargo feature `coverage`.
+//!
+//! With the feature, every function with `#[rewrite_forward_goto]` gets a static table with
+//! a counter for each goto, i.e. for each edge from a goto to its label.
+//! The table is registered when the function is called for the first time, so [`report`]
+//! lists all edges of the functions that were called, including the edges that were never taken.
+//! Generated gotos, like the ones of `fallthrough!()`, and the gotos of a `const fn` are not counted.
+//!
+//! ```
+//! use forward_goto::rewrite_forward_goto;
+//!
+//! #[rewrite_forward_goto]
+//! fn decode(byte: u8) -> Option<char> {
+//!     if byte >= 0x80 {
+//!         forward_goto!('invalid);
+//!     }
+//!     return Some(byte as char);
+//!
+//!     forward_label!('invalid);
+//!     None
+//! }
+//!
+//! decode(b'a');
+//! for edge in forward_goto::coverage::report() {
+//!     if edge.function == "decode" {
+//!         assert_eq!(edge.label, "'invalid");
+//!         assert_eq!(edge.hits, 0); // missing a seed with an invalid byte
+//!     }
+//! }
+//! ```
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// The counter of a goto. Created by the generated code.
+pub struct Edge {
+    function: &'static str,
+    label: &'static str,
+    file: &'static str,
+    line: u32,
+    hits: AtomicU64,
+}
+
+impl Edge {
+    pub const fn new(function: &'static str, label: &'static str, file: &'static str, line: u32) -> Self {
+        Self { function, label, file, line, hits: AtomicU64::new(0) }
+    }
+}
+
+/// The counters of the gotos of a function. Created by the generated code.
+pub struct Table {
+    edges: &'static [Edge],
+    registered: AtomicBool,
+}
+
+impl Table {
+    pub const fn new(edges: &'static [Edge]) -> Self {
+        Self { edges, registered: AtomicBool::new(false) }
+    }
+
+    /// Adds the table to the registry, if it is not registered yet.
+    pub fn register(&'static self) {
+        if !self.registered.load(Ordering::Relaxed) && !self.registered.swap(true, Ordering::Relaxed) {
+            REGISTRY.lock().unwrap_or_else(PoisonError::into_inner).push(self);
+        }
+    }
+
+    /// Counts that the goto with the index `edge` was taken.
+    pub fn hit(&self, edge: usize) {
+        self.edges[edge].hits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static REGISTRY: Mutex<Vec<&'static Table>> = Mutex::new(Vec::new());
+
+/// The number of times a goto was taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EdgeReport {
+    /// The name of the rewritten function.
+    pub function: &'static str,
+    /// The label the goto jumps to, e.g. `'fail`.
+    pub label: &'static str,
+    /// The source file of the goto.
+    pub file: &'static str,
+    /// The line of the goto in the source file.
+    pub line: u32,
+    /// How often the goto was taken.
+    pub hits: u64,
+}
+
+impl fmt::Display for EdgeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {} goto {}: {} hits", self.file, self.line, self.function, self.label, self.hits)
+    }
+}
+
+/// Returns the counters of all gotos in the functions that were called so far, ordered by their location.
+pub fn report() -> Vec<EdgeReport> {
+    let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut edges: Vec<_> = registry
+        .iter()
+        .flat_map(|table| table.edges)
+        .map(|edge| EdgeReport {
+            function: edge.function,
+            label: edge.label,
+            file: edge.file,
+            line: edge.line,
+            hits: edge.hits.load(Ordering::Relaxed),
+        })
+        .collect();
+    edges.sort_by_key(|edge| (edge.file, edge.line));
+    edges
+}
+
+/// Sets all counters to zero, e.g. between two runs of a fuzzer.
+pub fn reset() {
+    let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    for edge in registry.iter().flat_map(|table| table.edges) {
+        edge.hits.store(0, Ordering::Relaxed);
+    }
+}
@@ -33,17 +33,9 @@
 //! }
 //! ```
 
-extern crate proc_macro;
-extern crate proc_macro2;
-
-mod result;
-mod collector;
-
-use collector::Collector;
-use quote::{quote, quote_spanned};
-use syn::*;
-use result::{Result};
-
+#[cfg(feature = "coverage")]
+pub mod coverage;
+pub mod trace;
 
 /// This macro will rewrite the annotated function so that the control-flow
 /// will go from a goto `forward_goto!('label)` directly to a corresponding label
@@ -56,12 +48,16 @@ use result::{Result};
 /// 
 /// For gotos and labels apply multiple restrictions:
 /// 1. Every goto has at most one corresponding label, but multiple gotos can go to
-///    the same label.
+///    the same label. A goto jumps to the nearest following label with its name,
+///    so a name can be used again once all gotos to the previous label are resolved,
+///    e.g. in code that is generated by `macro_rules!` for every field of a struct.
 /// 2. Only forward jumps are allowed, meaning that the goto must come before the label.
 ///    in the code. Backward jumps are not allowed. 'Side jumps' 
 ///    (i.e. from a then-branch into an else-branch) are possible,
 ///    as long as the goto is physically before the label.
-/// 3. Any statement after a label in the control-flow may not be the result statement
+/// 3. A goto label may not have the same name as a loop label it is nested in.
+///    Apart from that, goto labels never clash with lifetimes or loop labels.
+/// 4. Any statement after a label in the control-flow may not be the result statement
 ///    of a block until all current labels are rewired to their corresponding gotos.
 /// 
 /// ```ignore
@@ -78,8 +74,34 @@ use result::{Result};
 /// }
 /// ```
 ///
+/// Lints like `unreachable_code` are only silenced for the generated loops
+/// and breaks, so dead code written by the user is still reported.
+///
+/// Attributes of gotos stay on the generated code, so a goto behind `#[cfg]` that is
+/// compiled out never jumps. Labels cannot be behind `#[cfg]`, neither directly nor
+/// inside a statement or match arm with it, because the loops generated for a label
+/// do not depend on the configuration. Put the `#[cfg]` on the statements next to the label instead.
+///
 /// Because of they way the rewriting is done, it is only possible to use
 /// definitions that are reachable on all code paths.
+/// Variables declared between a goto and its label are not available after the label.
+/// 
+/// ```compile_fail
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn test(b: bool) {
+///     if b {
+///         forward_goto!('skip);
+///     }
+/// 
+///     let x = 5;
+/// 
+///     forward_label!('skip);
+/// 
+///     // error: `x` is declared after goto 'skip and is not available after label 'skip
+///     println!("{}", x);
+/// }
+/// ```
 /// 
 /// ```
 /// # use forward_goto::rewrite_forward_goto;
@@ -102,224 +124,338 @@ use result::{Result};
 /// 
 ///     f1();
 /// }
-/// ``` 
+/// ```
+///
+/// # Conditional gotos
+///
+/// `forward_goto_if!(cond, 'label)` is a shorthand for `if cond { forward_goto!('label); }`.
+///
+/// `forward_goto_if_let!(pat = expr, 'label)` jumps to the label if `expr` does not match `pat`,
+/// like the `else` branch of `let pat = expr else { ... }`. Otherwise the bindings of `pat`
+/// are available for the rest of the block. It can only be used as a statement.
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn parse(input: Option<&str>) -> i32 {
+///     let mut number = -1;
+///
+///     forward_goto_if_let!(Some(text) = input, 'done);
+///     forward_goto_if!(text.is_empty(), 'done);
+///     number = text.len() as i32;
+///
+///     forward_label!('done);
+///     number
+/// }
+/// ```
+///
+/// `forward_goto_match!(expr { pat => 'label, ... })` matches `expr` and jumps to the label
+/// of the first matching arm. Arms can have guards, and arms without `=> 'label`
+/// continue after the macro. The generated `match` is checked for exhaustiveness by rustc.
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn classify(n: u32) -> &'static str {
+///     let mut class = "large";
+///
+///     forward_goto_match!(n {
+///         0 => 'zero,
+///         1 | 2 | 3 => 'small,
+///         _,
+///     });
+///     forward_goto!('done);
+///
+///     forward_label!('zero);
+///     class = "zero";
+///     forward_goto!('done);
+///
+///     forward_label!('small);
+///     class = "small";
+///
+///     forward_label!('done);
+///     class
+/// }
+/// ```
+///
+/// # Fallthrough
+///
+/// `fallthrough!()` at the end of an arm of a `match` statement continues with the body
+/// of the next arm, like a `case` without `break` in C. The next arm may not bind any variables,
+/// because its pattern did not match. Its body is moved behind the `match` and
+/// the arms that reach it jump to it, so it cannot result in a value.
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn copy(from: &[u8], to: &mut Vec<u8>) {
+///     let mut i = 0;
+///     // copies the remainder first, then four bytes per round
+///     match from.len() % 4 {
+///         3 => { to.push(from[i]); i += 1; fallthrough!() },
+///         2 => { to.push(from[i]); i += 1; fallthrough!() },
+///         1 => { to.push(from[i]); i += 1; },
+///         _ => {},
+///     }
+///     while i < from.len() {
+///         to.extend_from_slice(&from[i..i + 4]);
+///         i += 4;
+///     }
+/// }
+/// ```
+///
+/// # Computed gotos
+///
+/// `label_addr!('label)` (or `label_ref!('label)`) evaluates to a value that stands for the label,
+/// and `goto_dyn!(target)` jumps to the label that `target` stands for. The values are variants
+/// of an enum that is generated inside the function, so they are `Copy` and can be compared,
+/// stored in arrays and passed to other macros like `vec!`, but they cannot leave the function.
+///
+/// Every `goto_dyn!` can jump to every label that is taken by address, so all of these labels
+/// have to come after all `goto_dyn!`s of the function.
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn run(op: usize, a: i32, b: i32) -> i32 {
+///     let ops = [label_addr!('add), label_addr!('sub)];
+///     let result;
+///
+///     goto_dyn!(ops[op]);
+///
+///     forward_label!('add);
+///     result = a + b;
+///     forward_goto!('done);
+///
+///     forward_label!('sub);
+///     result = a - b;
+///
+///     forward_label!('done);
+///     result
+/// }
+/// ```
+///
+/// # Handlers
+///
+/// `forward_handler!('label, { ... })` is a label with a body that only runs when a goto jumps to it.
+/// Normal flow skips the body, so no `return` is needed in front of it. The body has to diverge,
+/// e.g. with `return` or `panic!`, and it can only use the variables that are available after the label.
+/// The statements behind the handler can still use everything that was declared before it,
+/// and gotos in front of the handler can jump over it to labels behind it.
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn add(a: Option<u32>, b: Option<u32>) -> Result<u32, &'static str> {
+///     let a = match a {
+///         Some(a) => a,
+///         None => forward_goto!('missing),
+///     };
+///     let b = match b {
+///         Some(b) => b,
+///         None => forward_goto!('missing),
+///     };
+///
+///     forward_handler!('missing, {
+///         return Err("missing operand");
+///     });
+///
+///     Ok(a + b)
+/// }
+/// ```
+///
+/// ```compile_fail
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn test(b: bool) {
+///     if b {
+///         forward_goto!('fail);
+///     }
+///
+///     // error: the handler does not diverge
+///     forward_handler!('fail, {
+///         println!("failed");
+///     });
+/// }
+/// ```
+///
+/// # Error gotos
+///
+/// `forward_try!(expr, 'label)` evaluates a `Result` like `?`: it yields the `Ok` value,
+/// and on `Err(err)` it converts the error with `From` and jumps to the label.
+/// The label has to bind the error with `forward_label!('label, err: Error)`
+/// or `forward_handler!('label, err: Error, { ... })`, so it can only be reached by `forward_try!`.
+/// The statement in front of `forward_label!` with a binding has to diverge, e.g. with `return`,
+/// because normal flow would reach the label without an error.
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto]
+/// fn parse_pair(a: &str, b: &str) -> Result<(u8, u8), String> {
+///     let a = forward_try!(a.parse(), 'fail);
+///     let b = forward_try!(b.parse(), 'fail);
+///
+///     forward_handler!('fail, err: std::num::ParseIntError, {
+///         return Err(format!("invalid number: {}", err));
+///     });
+///
+///     Ok((a, b))
+/// }
+/// ```
+///
+/// # Gotos in macros
+///
+/// The attribute runs before the macros in the function are expanded,
+/// so it cannot see gotos that a `macro_rules!` helper expands to.
+/// Instead, a label that is a whole argument of another macro, like `'fail` in `check!(x > 0, 'fail)`,
+/// counts as a goto from that statement. Lifetimes inside of other arguments, e.g. in `&'a str`,
+/// are left alone. The helper expands to [`forward_goto!`](macro@forward_goto),
+/// which this crate exports for that purpose:
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// macro_rules! check {
+///     ($cond:expr, $label:lifetime) => {
+///         if !$cond {
+///             forward_goto::forward_goto!($label);
+///         }
+///     };
+/// }
+///
+/// #[rewrite_forward_goto]
+/// fn is_digit(c: char) -> bool {
+///     check!(c >= '0', 'fail);
+///     check!(c <= '9', 'fail);
+///     return true;
+///
+///     forward_label!('fail);
+///     false
+/// }
+///
+/// assert!(is_digit('7'));
+/// assert!(!is_digit('x'));
+/// ```
+///
+/// The label has to be passed to the helper. `macro_rules!` is hygienic for labels,
+/// so a label that is written in the body of the helper can never refer to a label of the function.
+/// For the same reason, `forward_label!` cannot be produced by a helper.
+///
+/// ```compile_fail
+/// # use forward_goto::rewrite_forward_goto;
+/// macro_rules! label {
+///     ($label:lifetime) => {
+///         forward_goto::forward_label!($label);
+///     };
+/// }
+///
+/// #[rewrite_forward_goto]
+/// fn test() {
+///     // error: forward_label! has to be written directly in a function with #[rewrite_forward_goto]
+///     label!('end);
+/// }
+/// ```
+///
+/// # Other attributes
+///
+/// Attributes are expanded from the top down, so `#[rewrite_forward_goto]` sees the function
+/// as it was written if it comes first. This order always works:
+///
+/// ```ignore
+/// #[rewrite_forward_goto]
+/// #[tokio::main]
+/// async fn main() { ... }
+/// ```
+///
+/// If it comes after an attribute like `#[tokio::main]` or `#[tracing::instrument]`,
+/// the body may already be moved into an `async` block or a closure.
+/// If the function uses no gotos outside of closures and async blocks,
+/// the one closure or async block that does is rewritten instead, which covers these wrappers.
+/// A cleanup label in a closure with a return type uses the return type of the closure.
+/// It is an error if more than one closure or async block uses gotos.
+/// Other rewrites of the body by such attributes are not supported.
+///
+/// # Options
 /// 
-#[proc_macro_attribute]
-pub fn rewrite_forward_goto(_attr: proc_macro::TokenStream, item: proc_macro::TokenStream) -> proc_macro::TokenStream {
-    let mut input = parse_macro_input!(item as ItemFn);
-
-    let mut collector = Collector::new();
-
-    let result = traverse_boxed_block(&mut input.block, &mut collector);
-        
-    let output = match result.and(collector.check()) {
-        Ok(()) => {
-            proc_macro::TokenStream::from(quote!(
-                #[allow(unreachable_code)]
-                #input
-            ))
-        },
-        Err((span, msg)) => {
-
-            //eprintln!("{}", proc_macro::TokenStream::from(quote!(
-            //    #input
-            //)));
-            
-            let error = quote_spanned!(span=>
-                compile_error!(#msg)
-            );
-
-            input.block = parse_quote!(
-                {
-                    #error
-                }
-            );
-
-            proc_macro::TokenStream::from(quote!(#input))
-        },
-    };
-
-    //eprintln!("done");
-    //eprintln!("{}", &output);
-    output
-}
-
-fn traverse_boxed_block(boxed: &mut Box<Block>, collector: &mut Collector) -> Result<()> {
-    traverse_stmts(&mut boxed.stmts, collector)
-}
-
-fn traverse_block(block: &mut Block, collector: &mut Collector) -> Result<()> {
-    traverse_stmts(&mut block.stmts, collector)
-}
-
-fn traverse_stmts(stmts: &mut Vec<Stmt>, collector: &mut Collector) -> Result<()> {
-    let mut i = 0;
-    while i < stmts.len() {
-        //eprintln!("start stmt");
-        {
-            let stmt = stmts.get_mut(i).unwrap();
-            let mut collector = collector.enter_statement(i);
-            traverse_stmt(stmt, &mut collector)?;
-        }
-
-        if let Some((start_index, end_label, continuations)) = collector.retrieve_continuations() {
-            //eprintln!("build goto {}", i);
-            let rest = stmts.split_off(i + 1);
-
-            i = start_index;
-            let mut inner = stmts.split_off(start_index);
-            inner.push(new_break_stmt(end_label.clone()));
-
-            for (incomings, continuation, outgoing) in continuations {
-                inner = {
-                    let mut inside_stmts = inner;
-
-                    if let Some(last) = incomings.last().cloned() {
-                        for incoming in incomings.into_iter() {
-                            inside_stmts.push(new_break_stmt(last.clone()));
-                            inside_stmts = vec![new_loop_block(incoming, inside_stmts)];
-                        }
-                    }
-
-                    inside_stmts.extend(continuation);
-                    inside_stmts.push(new_break_stmt(outgoing));
-                    inside_stmts
-                }
-            }
-            stmts.push(new_loop_block(end_label, inner));
-            stmts.extend(rest);
-            //eprintln!("finished build goto {} in {}", i, stmts.len());
-            continue;
-        }
-
-
-        if collector.should_push_continuation() {
-            let mut continuation = stmts.split_off(i + 1);
-            //eprintln!("push continuation {}", continuation.len());
-            if let Some(stmt@Stmt::Expr(_)) = continuation.last_mut() {
-                //eprintln!("err");
-                collector.add_error(stmt, "Result statement is in label continuation and cannot result in a value. Consider adding ';'");
-            }
-            let target = collector.push_continuation(continuation);
-            stmts.push(expr_to_stmt(new_break_expr(target)));
-            //eprintln!("pushed continuation");
-            return Ok(());
-        }
-        //eprintln!("end stmt");
-
-        i += 1;
-    }
-
-    Ok(())
-}
-
-fn traverse_stmt(stmt: &mut Stmt, collector: &mut Collector) -> Result<()> {
-    match stmt {
-        Stmt::Item(_) => Ok(()),
-        Stmt::Local(local) => {
-            match local.init {
-                Some((_, ref mut expr_box)) => 
-                    traverse_boxed_expr(expr_box, collector),
-                None => Ok(()),
-            }
-        },
-        Stmt::Expr(expr) => traverse_expr(expr, collector, true),
-        Stmt::Semi(expr, _) => traverse_expr(expr, collector, true),
-    }
-}
-
-fn traverse_boxed_expr(expr: &mut Box<Expr>, collector: &mut Collector) -> Result<()> {
-    traverse_expr(expr, collector, false)
-}
-
-fn traverse_expr(expr: &mut Expr, collector: &mut Collector, _is_statement: bool) -> Result<()> {
-    let replacement_expr = match expr {
-        Expr::Macro(mac) => {
-            let mac = &mac.mac;
-            let path = &mac.path;
-            let forward_macro = path.is_ident("forward_goto") || path.is_ident("forward_label");
-            if forward_macro {
-                let tokens = &mac.tokens;
-                let lifetime: Lifetime = parse2(tokens.clone()).unwrap();
-
-                //eprintln!("found macro");
-                if path.is_ident("forward_goto") {
-                    collector.add_goto(lifetime.clone());
-                } else {
-                    collector.add_label(lifetime.clone())?;
-                }
-
-                Some(new_break_expr(lifetime))
-            } else {
-                None
-            }
-        },
-        Expr::If(ExprIf { cond, then_branch, else_branch, .. }) => {
-            traverse_boxed_expr(cond, &mut collector.cut())?;
-            traverse_block(then_branch, &mut collector.enter())?;
-            if let Some((_, expr)) = else_branch {
-                //eprintln!("traverse else");
-                traverse_boxed_expr(expr, &mut collector.enter())?;
-            }
-            None
-        },
-        Expr::Match(ExprMatch { expr, arms, .. }) => {
-            traverse_boxed_expr(expr, &mut collector.cut())?;
-            for arm in arms.iter_mut() {
-                traverse_boxed_expr(&mut arm.body, &mut collector.enter())?;
-            }
-            None
-        },
-        Expr::Block(ExprBlock { block, ..}) => {
-            traverse_block(block, &mut collector.enter())?;
-            None
-        },
-        Expr::Let(ExprLet { expr, .. }) => {
-            traverse_boxed_expr(expr, &mut collector.cut())?;
-            None
-        },
-        Expr::Loop(ExprLoop { body, .. }) => {
-            traverse_block(body, &mut collector.enter())?;
-            None
-        },
-        _ => None,
-    };
-
-    if let Some(replacement) = replacement_expr {
-        *expr = replacement;
-    }
-
-    Ok(())
-}
-
-fn new_break_stmt(lifetime: Lifetime) -> Stmt {
-    expr_to_stmt(new_break_expr(lifetime))
-}
-
-fn expr_to_stmt(expr: Expr) -> Stmt {
-    Stmt::Semi(expr, Token![;](proc_macro2::Span::call_site()))
-}
+/// Options are given as arguments to the attribute, e.g. `#[rewrite_forward_goto(hoist)]`.
+/// 
+/// - `hoist`: Variables that are declared between a goto and its label and used after the label
+///   are declared in front of the generated wrapper instead. Their initialization becomes an assignment,
+///   so rustc's definite-initialization check decides whether a use after the label is valid.
+///   A variable cannot be hoisted if its name is also used for another binding after the goto,
+///   e.g. in `let x = x + 1;`.
+/// - `debug`: The rewritten function is pretty-printed to stderr during compilation,
+///   together with a summary of which statements were wrapped for each label.
+///   The summary has line numbers if the feature `span-locations` of `forward_goto_core` is enabled.
+/// - `cleanup = 'label`: Every `return` and `?` in front of `forward_label!('label);` stores its value
+///   and jumps to the label, which has to be a statement of the function body.
+///   The function returns the stored value after the statements behind the label,
+///   so the last of them cannot be an expression without `;`. Unless the function returns `()`, the statement in front of the label has to diverge,
+///   e.g. with `return`, because normal flow would reach the label without a value.
+///   `?` is supported in functions whose return type is written as `Result` or `Option`,
+///   not through another type alias.
+///   Closures and async blocks keep their own `return` and `?`. The arguments of other macros,
+///   like `format!`, are not rewritten, so a `?` in them returns without running the cleanup.
+/// - `trace`: Every goto and label reports when it is passed at runtime, see the `trace` module.
+/// - `crate = "path"`: The path of this crate used by `trace` and `coverage`, `::forward_goto` by default.
+///
+/// Setting the environment variable `FORWARD_GOTO_DUMP` to a directory writes the same output
+/// for every rewritten function to `<dir>/<function name>.rs`. Functions with the same name overwrite each other.
+/// Cargo does not track this variable, so it only affects crates that are compiled anyway,
+/// e.g. after `cargo clean -p <crate>`.
+///
+/// With the cargo feature `coverage`, every goto is counted, see the `coverage` module.
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// #[rewrite_forward_goto(hoist)]
+/// fn test(b: bool) -> i32 {
+///     if b {
+///         forward_goto!('skip);
+///     }
+/// 
+///     let mut x = 5;
+///     x += 1;
+/// 
+///     forward_label!('skip);
+/// 
+///     x = 7; // x is still in scope and gets initialized on all paths
+///     x
+/// }
+/// ```
+///
+/// ```
+/// # use forward_goto::rewrite_forward_goto;
+/// # use std::cell::Cell;
+/// #[rewrite_forward_goto(cleanup = 'unlock)]
+/// fn first_even(locked: &Cell<bool>, numbers: &[&str]) -> Result<Option<u32>, std::num::ParseIntError> {
+///     locked.set(true);
+///
+///     for number in numbers {
+///         let number: u32 = number.parse()?;
+///         if number % 2 == 0 {
+///             return Ok(Some(number));
+///         }
+///     }
+///     return Ok(None);
+///
+///     forward_label!('unlock);
+///     locked.set(false);
+/// }
+///
+/// let locked = Cell::new(false);
+/// assert_eq!(first_even(&locked, &["1", "4"]), Ok(Some(4)));
+/// assert!(first_even(&locked, &["x"]).is_err());
+/// assert!(!locked.get());
+/// ```
+pub use forward_goto_macros::rewrite_forward_goto;
 
-fn new_break_expr(lifetime: Lifetime) -> Expr {
-    Expr::Break(ExprBreak {
-        attrs: Vec::new(),
-        break_token: Token![break](proc_macro2::Span::call_site()),
-        label: Some(lifetime),
-        expr: None,
-    })
-}
+/// Jumps to `'label`, when it is expanded from a helper macro in a function with [`rewrite_forward_goto`].
+///
+/// Gotos written directly in such a function are rewritten by the attribute and never reach this macro.
+/// See [Gotos in macros](macro@rewrite_forward_goto#gotos-in-macros).
+pub use forward_goto_macros::forward_goto;
 
-fn new_loop_block(label: Lifetime, body: Vec<Stmt>) -> Stmt {
-    expr_to_stmt(Expr::Loop(ExprLoop {
-        attrs: Vec::new(),
-        label: Some(Label {
-            name: label,
-            colon_token: Token![:](proc_macro2::Span::call_site()),
-        }),
-        loop_token: Token![loop](proc_macro2::Span::call_site()),
-        body: Block {
-            brace_token: token::Brace { span: proc_macro2::Span::call_site() },
-            stmts: body,
-        },
-    }))
-}
+/// Labels are only understood by [`rewrite_forward_goto`], so this macro always reports an error.
+///
+/// It is only expanded if `forward_label!` is produced by another macro or used outside of a rewritten function.
+pub use forward_goto_macros::forward_label;
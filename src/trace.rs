@@ -0,0 +1,107 @@
+//! Runtime support for `#[rewrite_forward_goto(trace)]`.
+//!
+//! With the `trace` option, every goto and every label of the function calls [`hit`]
+//! when it is passed. The hits go to the hook set with [`set_hook`], to [`record`] on the current thread,
+//! and with the cargo feature `trace` to the `log` facade at the level `trace`.
+//! Functions without the option contain no calls at all. The gotos of `fallthrough!()` are not reported.
+//!
+//! ```
+//! use forward_goto::rewrite_forward_goto;
+//! use forward_goto::trace::{self, Event};
+//!
+//! #[rewrite_forward_goto(trace)]
+//! fn sign(x: i32) -> i32 {
+//!     if x < 0 {
+//!         forward_goto!('negative);
+//!     }
+//!     return 1;
+//!
+//!     forward_label!('negative);
+//!     -1
+//! }
+//!
+//! let (result, hits) = trace::record(|| sign(-5));
+//! assert_eq!(result, -1);
+//! let path: Vec<_> = hits.iter().map(|hit| (hit.event, hit.label)).collect();
+//! assert_eq!(path, [(Event::Goto, "'negative"), (Event::Label, "'negative")]);
+//! ```
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::{PoisonError, RwLock};
+
+/// What happened at a goto or label.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Event {
+    /// A goto jumped to its label.
+    Goto,
+    /// A label was reached, either by a goto or by the normal control flow.
+    Label,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Goto => write!(f, "goto"),
+            Event::Label => write!(f, "label"),
+        }
+    }
+}
+
+/// A goto or label that was passed at runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Hit {
+    /// The name of the rewritten function.
+    pub function: &'static str,
+    /// Whether a goto jumped or a label was reached.
+    pub event: Event,
+    /// The label of the goto or the label itself, e.g. `'fail`.
+    pub label: &'static str,
+    /// The line of the goto or label in the source file.
+    pub line: u32,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} {} (line {})", self.function, self.event, self.label, self.line)
+    }
+}
+
+static HOOK: RwLock<Option<fn(&Hit)>> = RwLock::new(None);
+
+thread_local! {
+    static RECORDING: RefCell<Option<Vec<Hit>>> = const { RefCell::new(None) };
+}
+
+/// Sets the hook that is called for every hit on any thread, or removes it.
+pub fn set_hook(hook: Option<fn(&Hit)>) {
+    *HOOK.write().unwrap_or_else(PoisonError::into_inner) = hook;
+}
+
+/// Calls `f` and returns the hits on the current thread while it ran.
+///
+/// A nested call records the hits of its own `f` only.
+pub fn record<T>(f: impl FnOnce() -> T) -> (T, Vec<Hit>) {
+    let outer = RECORDING.with(|recording| recording.replace(Some(Vec::new())));
+    let result = f();
+    let hits = RECORDING.with(|recording| recording.replace(outer)).unwrap_or_default();
+    (result, hits)
+}
+
+/// Reports a passed goto or label. The calls are generated by `#[rewrite_forward_goto(trace)]`.
+pub fn hit(function: &'static str, event: Event, label: &'static str, line: u32) {
+    let hit = Hit { function, event, label, line };
+
+    #[cfg(feature = "trace")]
+    log::trace!(target: "forward_goto", "{}", hit);
+
+    RECORDING.with(|recording| {
+        if let Some(hits) = recording.borrow_mut().as_mut() {
+            hits.push(hit);
+        }
+    });
+
+    if let Some(hook) = *HOOK.read().unwrap_or_else(PoisonError::into_inner) {
+        hook(&hit);
+    }
+}
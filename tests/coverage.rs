@@ -0,0 +1,75 @@
+use forward_goto::coverage::{self, EdgeReport};
+use forward_goto::rewrite_forward_goto;
+
+#[rewrite_forward_goto]
+fn test_coverage_method(x: u32) -> Result<u32, String> {
+    forward_goto_if!(x == 0, 'zero);
+    let half = forward_try!(if x & 1 == 0 { Ok(x / 2) } else { Err("odd") }, 'fail);
+    return Ok(half);
+
+    forward_label!('fail, err: &str);
+    return Err(err.into());
+
+    forward_label!('zero);
+    Ok(0)
+}
+
+#[rewrite_forward_goto]
+fn test_coverage_uncalled_method(b: bool) {
+    if b {
+        forward_goto!('skip);
+    }
+    forward_label!('skip);
+}
+
+#[rewrite_forward_goto]
+const fn test_coverage_const_method(x: u32) -> u32 {
+    if x == 0 {
+        forward_goto!('zero);
+    }
+    return 100 / x;
+
+    forward_label!('zero);
+    0
+}
+
+fn edges(function: &str) -> Vec<EdgeReport> {
+    coverage::report().into_iter().filter(|edge| edge.function == function).collect()
+}
+
+#[test]
+fn test_coverage() {
+    assert_eq!(edges("test_coverage_method"), vec![]);
+
+    test_coverage_method(4).unwrap();
+    test_coverage_method(0).unwrap();
+    test_coverage_method(8).unwrap();
+
+    let report = edges("test_coverage_method");
+    let hits: Vec<_> = report.iter().map(|edge| (edge.label, edge.hits)).collect();
+    assert_eq!(hits, [("'zero", 1), ("'fail", 0)]);
+    assert_eq!(report[0].file, file!());
+    assert_eq!(report[1].line, report[0].line + 1);
+    assert_eq!(
+        report[1].to_string(),
+        format!("{}:{}: test_coverage_method goto 'fail: 0 hits", file!(), report[1].line)
+    );
+
+    test_coverage_method(3).unwrap_err();
+    let hits: Vec<_> = edges("test_coverage_method").iter().map(|edge| edge.hits).collect();
+    assert_eq!(hits, [1, 1]);
+
+    // functions are only listed after they were called
+    let _: fn(bool) = test_coverage_uncalled_method;
+    assert_eq!(edges("test_coverage_uncalled_method"), vec![]);
+
+    // const fns are not instrumented
+    const HUNDRED: u32 = test_coverage_const_method(1);
+    assert_eq!(HUNDRED, 100);
+    assert_eq!(test_coverage_const_method(0), 0);
+    assert_eq!(edges("test_coverage_const_method"), vec![]);
+
+    coverage::reset();
+    let hits: Vec<_> = edges("test_coverage_method").iter().map(|edge| edge.hits).collect();
+    assert_eq!(hits, [0, 0]);
+}
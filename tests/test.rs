@@ -1,4 +1,8 @@
+#![deny(unreachable_code)]
+#![allow(clippy::unnecessary_cast)]
+
 use forward_goto::*;
+use forward_goto_test_wrappers::{wrap_async, wrap_closure};
 
 #[derive(Eq, PartialEq)]
 enum Three {
@@ -7,6 +11,7 @@ enum Three {
 
 
 #[rewrite_forward_goto]
+#[allow(unreachable_code)]
 fn test_easy_method() -> Vec<&'static str>{
     let mut result = vec!["begin"];
 
@@ -581,6 +586,7 @@ fn test_jump_in_continuation() {
 
 
 #[rewrite_forward_goto]
+#[allow(unreachable_code)]
 fn test_jump_into_match_method(three: Three) -> Vec<&'static str>{
     let mut result = vec!["begin"];
 
@@ -820,6 +826,7 @@ fn test_side_jump() {
 
 
 #[rewrite_forward_goto]
+#[allow(unreachable_code)]
 fn test_jump_into_continuation_method(b: bool) -> Vec<&'static str>{
     let mut result = vec!["begin"];
 
@@ -869,3 +876,1135 @@ fn test_jump_into_continuation() {
 }
 
 
+
+
+#[rewrite_forward_goto]
+fn test_locals_around_jump_method(b: bool) -> Vec<String> {
+    let mut result = vec!["begin".to_string()];
+    let before = "before";
+
+    if b {
+        forward_goto!('test);
+    }
+
+    let between = "between";
+    result.push(between.to_string());
+
+    forward_label!('test);
+
+    let after = "after";
+    result.push(format!("{} {}", before, after));
+    result
+}
+
+#[test]
+fn test_locals_around_jump() {
+    assert_eq!(test_locals_around_jump_method(true),
+        vec![
+            "begin",
+            "before after",
+        ]
+    );
+
+    assert_eq!(test_locals_around_jump_method(false),
+        vec![
+            "begin",
+            "between",
+            "before after",
+        ]
+    );
+}
+
+
+#[rewrite_forward_goto(hoist)]
+fn test_hoist_method(b: bool) -> Vec<String> {
+    let mut result = vec!["begin".to_string()];
+
+    if b {
+        forward_goto!('test);
+    }
+
+    let mut counter = 1;
+    let (mut name, _): (&str, i32) = ("between", 0);
+    result.push(format!("{} {}", name, counter));
+
+    forward_label!('test);
+
+    counter = 10;
+    name = "after";
+    counter += 1;
+
+    result.push(format!("{} {}", name, counter));
+    result
+}
+
+#[test]
+fn test_hoist() {
+    assert_eq!(test_hoist_method(true),
+        vec![
+            "begin",
+            "after 11",
+        ]
+    );
+
+    assert_eq!(test_hoist_method(false),
+        vec![
+            "begin",
+            "between 1",
+            "after 11",
+        ]
+    );
+}
+
+#[rewrite_forward_goto(hoist)]
+fn test_hoist_patterns_method(b: bool, pair: (i32, i32)) -> Vec<i32> {
+    let mut result = Vec::new();
+    let x = 1;
+    let x = x + 1;
+
+    if b {
+        forward_goto!('skip);
+    }
+
+    let (mut a, mut c) = pair;
+    c += a;
+    let [mut d, _] = [x * 10, 0];
+    let mut y = {
+        let x = c;
+        x + 100
+    };
+    result.extend([a, c, d, y]);
+
+    forward_label!('skip);
+
+    a = x;
+    c = a + 1;
+    d = c + 1;
+    y = d + 1;
+    result.extend([a, c, d, y]);
+    result
+}
+
+#[test]
+fn test_hoist_patterns() {
+    assert_eq!(test_hoist_patterns_method(false, (3, 4)), [3, 7, 20, 107, 2, 3, 4, 5]);
+    assert_eq!(test_hoist_patterns_method(true, (3, 4)), [2, 3, 4, 5]);
+}
+
+#[rewrite_forward_goto(hoist)]
+fn test_hoist_ref_mut_method(b: bool) -> (i32, i32) {
+    let mut pair = (1, 2);
+
+    if b {
+        forward_goto!('skip);
+    }
+
+    let (ref mut x, mut y) = pair;
+    *x += y;
+
+    forward_label!('skip);
+
+    y = 10;
+    (pair.0, y)
+}
+
+#[test]
+fn test_hoist_ref_mut() {
+    assert_eq!(test_hoist_ref_mut_method(false), (3, 10));
+    assert_eq!(test_hoist_ref_mut_method(true), (1, 10));
+}
+
+
+#[rewrite_forward_goto]
+fn test_label_names_method<'a>(input: &'a str, other: &str, b: bool) -> Vec<&'a str> {
+    let mut result = vec!["begin"];
+
+    if b {
+        forward_goto!('a);
+    }
+
+    if !b {
+        forward_goto!('_continuation0);
+    }
+
+    result.push("not executed");
+
+    forward_label!('a);
+
+    result.push(input);
+
+    forward_label!('_continuation0);
+
+    'outer: loop {
+        result.push("in loop");
+        if result.len() > other.len() {
+            break 'outer;
+        }
+    }
+
+    result.push("end");
+    result
+}
+
+#[test]
+fn test_label_names() {
+    assert_eq!(test_label_names_method("input", "", true),
+        vec![
+            "begin",
+            "input",
+            "in loop",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_label_names_method("input", "", false),
+        vec![
+            "begin",
+            "in loop",
+            "end",
+        ]
+    );
+}
+
+
+#[rewrite_forward_goto]
+fn test_goto_if_method(n: i32) -> Vec<&'static str> {
+    let mut result = vec!["begin"];
+
+    forward_goto_if!(n < 0, 'negative);
+    result.push("not negative");
+    forward_goto_if!(n == 0, 'zero);
+    result.push("positive");
+
+    forward_label!('negative);
+    result.push("negative or positive");
+
+    forward_label!('zero);
+    result.push("end");
+    result
+}
+
+#[test]
+fn test_goto_if() {
+    assert_eq!(test_goto_if_method(-1),
+        vec![
+            "begin",
+            "negative or positive",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_if_method(0),
+        vec![
+            "begin",
+            "not negative",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_if_method(1),
+        vec![
+            "begin",
+            "not negative",
+            "positive",
+            "negative or positive",
+            "end",
+        ]
+    );
+}
+
+
+#[rewrite_forward_goto]
+fn test_goto_if_let_method(input: Option<(i32, &str)>) -> Vec<String> {
+    let mut result = vec!["begin".to_string()];
+
+    {
+        forward_goto_if_let!(Some((mut number, name)) = input, 'missing);
+        number += 1;
+        result.push(format!("{} {}", name, number));
+
+        forward_goto_if_let!(Ok(half) = if number % 2 == 0 { Ok(number / 2) } else { Err(number) }, 'missing);
+        result.push(format!("half {}", half));
+
+        let mut quarter = Some(half / 2);
+        forward_goto_if_let!(Some(ref mut value) = quarter, 'missing);
+        *value += 1;
+        result.push(format!("quarter {:?}", quarter));
+    }
+
+    forward_label!('missing);
+
+    result.push("end".to_string());
+    result
+}
+
+#[test]
+fn test_goto_if_let() {
+    assert_eq!(test_goto_if_let_method(Some((1, "one"))),
+        vec![
+            "begin",
+            "one 2",
+            "half 1",
+            "quarter Some(1)",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_if_let_method(Some((2, "two"))),
+        vec![
+            "begin",
+            "two 3",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_if_let_method(None),
+        vec![
+            "begin",
+            "end",
+        ]
+    );
+}
+
+
+#[rewrite_forward_goto(hoist)]
+fn test_goto_if_let_hoist_method(input: Result<i32, i32>) -> Vec<String> {
+    let mut result = vec!["begin".to_string()];
+
+    forward_goto_if_let!(Ok(mut value) = input, 'failed);
+    value *= 2;
+    result.push(format!("ok {}", value));
+
+    forward_label!('failed);
+
+    value = 0;
+    result.push(format!("end {}", value));
+    result
+}
+
+#[test]
+fn test_goto_if_let_hoist() {
+    assert_eq!(test_goto_if_let_hoist_method(Ok(4)),
+        vec![
+            "begin",
+            "ok 8",
+            "end 0",
+        ]
+    );
+
+    assert_eq!(test_goto_if_let_hoist_method(Err(4)),
+        vec![
+            "begin",
+            "end 0",
+        ]
+    );
+}
+
+
+#[rewrite_forward_goto]
+fn test_goto_match_method(n: u32) -> Vec<&'static str> {
+    let mut result = vec!["begin"];
+
+    forward_goto_match!(n {
+        0 => 'zero,
+        1 | 2 => 'small,
+        x if x % 2 == 0 => 'even,
+        _,
+    });
+    result.push("odd");
+    forward_goto!('end);
+
+    forward_label!('zero);
+    result.push("zero");
+
+    forward_label!('small);
+    result.push("small");
+
+    forward_label!('even);
+    result.push("even");
+
+    forward_label!('end);
+    result.push("end");
+    result
+}
+
+#[test]
+fn test_goto_match() {
+    assert_eq!(test_goto_match_method(0),
+        vec![
+            "begin",
+            "zero",
+            "small",
+            "even",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_match_method(2),
+        vec![
+            "begin",
+            "small",
+            "even",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_match_method(4),
+        vec![
+            "begin",
+            "even",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_match_method(5),
+        vec![
+            "begin",
+            "odd",
+            "end",
+        ]
+    );
+}
+
+
+#[rewrite_forward_goto]
+fn test_fallthrough_method(count: usize) -> Vec<usize> {
+    let mut result = Vec::new();
+    let mut n = 0;
+    let mut rounds = count.div_ceil(4);
+
+    // Duff's device
+    match count % 4 {
+        0 => {
+            result.push(n);
+            n += 1;
+            fallthrough!();
+        },
+        3 => {
+            result.push(n);
+            n += 1;
+            fallthrough!()
+        },
+        2 => {
+            result.push(n);
+            n += 1;
+            fallthrough!()
+        },
+        _ => {
+            result.push(n);
+            n += 1;
+        },
+    }
+    rounds -= 1;
+
+    while rounds > 0 {
+        for _ in 0..4 {
+            result.push(n);
+            n += 1;
+        }
+        rounds -= 1;
+    }
+
+    result
+}
+
+#[test]
+fn test_fallthrough() {
+    for count in 1..10 {
+        assert_eq!(test_fallthrough_method(count), (0..count).collect::<Vec<_>>());
+    }
+}
+
+
+#[rewrite_forward_goto]
+fn test_fallthrough_state_method(state: u8) -> Vec<&'static str> {
+    let mut result = vec!["begin"];
+
+    match state {
+        0 => fallthrough!(),
+        1 => result.push("connect"),
+        2 => {
+            result.push("handshake");
+            fallthrough!();
+        },
+        _ => result.push("send"),
+    }
+
+    result.push("end");
+    result
+}
+
+#[test]
+fn test_fallthrough_state() {
+    assert_eq!(test_fallthrough_state_method(0), vec!["begin", "connect", "end"]);
+    assert_eq!(test_fallthrough_state_method(1), vec!["begin", "connect", "end"]);
+    assert_eq!(test_fallthrough_state_method(2), vec!["begin", "handshake", "send", "end"]);
+    assert_eq!(test_fallthrough_state_method(3), vec!["begin", "send", "end"]);
+}
+
+
+#[rewrite_forward_goto]
+fn test_goto_dyn_method(op: usize) -> Vec<&'static str> {
+    let mut result = vec!["begin"];
+    let table = [label_addr!('add), label_addr!('sub), label_ref!('done)];
+    let target = table[op];
+    assert_eq!(target, table[op]);
+
+    goto_dyn!(target);
+
+    forward_label!('add);
+    result.push("add");
+    forward_goto!('done);
+
+    forward_label!('sub);
+    result.push("sub");
+
+    forward_label!('done);
+    result.push("end");
+    result
+}
+
+#[test]
+fn test_goto_dyn() {
+    assert_eq!(test_goto_dyn_method(0),
+        vec![
+            "begin",
+            "add",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_dyn_method(1),
+        vec![
+            "begin",
+            "sub",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_goto_dyn_method(2),
+        vec![
+            "begin",
+            "end",
+        ]
+    );
+}
+
+
+#[rewrite_forward_goto]
+fn test_handler_method(a: Option<u32>, b: Option<u32>) -> Result<u32, Vec<&'static str>> {
+    let mut result = vec!["begin"];
+
+    let x = match a {
+        Some(x) => x,
+        None => forward_goto!('fail),
+    };
+    result.push("a");
+
+    if b.is_none() {
+        forward_goto!('fail);
+    }
+    result.push("b");
+
+    forward_handler!('fail, {
+        result.push("fail");
+        return Err(result);
+    });
+
+    let sum = x + b.unwrap();
+    if sum > 10 {
+        forward_goto!('too_large);
+    }
+
+    forward_handler!('too_large, {
+        result.push("too large");
+        return Err(result);
+    });
+
+    Ok(sum)
+}
+
+#[test]
+fn test_handler() {
+    assert_eq!(test_handler_method(Some(1), Some(2)), Ok(3));
+
+    assert_eq!(test_handler_method(None, Some(2)),
+        Err(vec![
+            "begin",
+            "fail",
+        ])
+    );
+
+    assert_eq!(test_handler_method(Some(1), None),
+        Err(vec![
+            "begin",
+            "a",
+            "fail",
+        ])
+    );
+
+    assert_eq!(test_handler_method(Some(10), Some(2)),
+        Err(vec![
+            "begin",
+            "a",
+            "b",
+            "too large",
+        ])
+    );
+}
+
+#[rewrite_forward_goto]
+fn test_handler_skip_method(x: i32) -> i32 {
+    if x > 5 {
+        forward_goto!('done);
+    }
+    if x < 0 {
+        forward_goto!('fail);
+    }
+
+    forward_handler!('fail, {
+        return -1;
+    });
+
+    return x * 2;
+
+    forward_label!('done);
+    x
+}
+
+#[test]
+fn test_handler_skip() {
+    assert_eq!(test_handler_skip_method(7), 7);
+    assert_eq!(test_handler_skip_method(-3), -1);
+    assert_eq!(test_handler_skip_method(2), 4);
+}
+
+#[rewrite_forward_goto]
+fn test_handler_shadow_method(input: Option<&str>) -> Result<usize, &'static str> {
+    let mut input = match input {
+        Some(input) => input,
+        None => forward_goto!('missing),
+    };
+    let len = input.len();
+
+    forward_handler!('missing, {
+        return Err("missing");
+    });
+
+    input = input.trim();
+    Ok(len - input.len())
+}
+
+#[test]
+fn test_handler_shadow() {
+    assert_eq!(test_handler_shadow_method(Some(" a ")), Ok(2));
+    assert_eq!(test_handler_shadow_method(None), Err("missing"));
+}
+
+
+#[derive(Debug, PartialEq)]
+enum TryError {
+    Parse(std::num::ParseIntError),
+    Zero,
+}
+
+impl From<std::num::ParseIntError> for TryError {
+    fn from(err: std::num::ParseIntError) -> Self {
+        TryError::Parse(err)
+    }
+}
+
+fn non_zero(n: u32) -> Result<u32, TryError> {
+    if n == 0 { Err(TryError::Zero) } else { Ok(n) }
+}
+
+#[rewrite_forward_goto]
+fn test_try_method(input: &str) -> (Result<u32, TryError>, Vec<&'static str>) {
+    let mut result = vec!["begin"];
+    // the generated variables cannot collide with the variables of the user
+    let __forward_goto_error_fail = "cleanup";
+
+    let n: u32 = forward_try!(input.parse(), 'fail);
+    result.push("parsed");
+    let n = forward_try!(non_zero(n), 'fail);
+    result.push("non zero");
+    return (Ok(100 / n), result);
+
+    forward_label!('fail, err: TryError);
+    result.push(__forward_goto_error_fail);
+    (Err(err), result)
+}
+
+#[rewrite_forward_goto]
+fn test_try_handler_method(input: &str) -> Result<u32, String> {
+    let n = forward_try!(input.parse::<u32>(), 'fail);
+    let n = forward_try!(non_zero(n), 'fail);
+
+    forward_handler!('fail, err: TryError, {
+        return Err(format!("{:?}", err));
+    });
+
+    Ok(100 / n)
+}
+
+#[test]
+fn test_try() {
+    assert_eq!(test_try_method("4"), (Ok(25), vec!["begin", "parsed", "non zero"]));
+    assert_eq!(test_try_method("0"), (Err(TryError::Zero), vec!["begin", "parsed", "cleanup"]));
+    assert!(matches!(test_try_method("x"), (Err(TryError::Parse(_)), ref trace) if *trace == ["begin", "cleanup"]));
+
+    assert_eq!(test_try_handler_method("5"), Ok(20));
+    assert_eq!(test_try_handler_method("0"), Err("Zero".to_owned()));
+}
+
+
+#[rewrite_forward_goto(cleanup = 'cleanup)]
+fn test_cleanup_method(input: &str, log: &mut Vec<String>) -> Result<u32, TryError> {
+    let __forward_goto_return = "unlock";
+    log.push("lock".to_owned());
+
+    if input.is_empty() {
+        return Ok(0);
+    }
+    let n: u32 = input.parse()?;
+    let n = non_zero(n)?;
+    log.push(format!("parsed {}", n));
+    return Ok(100 / n);
+
+    forward_label!('cleanup);
+    log.push(__forward_goto_return.to_owned());
+}
+
+#[rewrite_forward_goto(cleanup = 'cleanup)]
+fn test_cleanup_option_method(values: &[u32], log: &mut Vec<&'static str>) -> Option<u32> {
+    let first = *values.first()?;
+    let closure = |x: u32| -> Option<u32> { x.checked_sub(1) };
+    let result = closure(first)?;
+    log.push("computed");
+    return Some(result);
+
+    forward_label!('cleanup);
+    log.push("cleanup");
+}
+
+#[test]
+fn test_cleanup() {
+    let mut log = Vec::new();
+    assert_eq!(test_cleanup_method("", &mut log), Ok(0));
+    assert_eq!(log, ["lock", "unlock"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_cleanup_method("4", &mut log), Ok(25));
+    assert_eq!(log, ["lock", "parsed 4", "unlock"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_cleanup_method("0", &mut log), Err(TryError::Zero));
+    assert_eq!(log, ["lock", "unlock"]);
+
+    let mut log = Vec::new();
+    assert!(matches!(test_cleanup_method("x", &mut log), Err(TryError::Parse(_))));
+    assert_eq!(log, ["lock", "unlock"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_cleanup_option_method(&[3], &mut log), Some(2));
+    assert_eq!(log, ["computed", "cleanup"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_cleanup_option_method(&[0], &mut log), None);
+    assert_eq!(log, ["cleanup"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_cleanup_option_method(&[], &mut log), None);
+    assert_eq!(log, ["cleanup"]);
+}
+
+
+#[rewrite_forward_goto]
+fn test_nested_goto_method(values: &[i32], limit: i32) -> Vec<&'static str> {
+    let mut result = vec!["begin"];
+    let mut i = 0;
+    while i < values.len() {
+        if values[i] < 0 {
+            forward_goto!('negative);
+        }
+        i += 1;
+    }
+    for &value in values {
+        if value > limit {
+            forward_goto!('large);
+        }
+    }
+    result.push(check_limit(if limit == 0 { forward_goto!('zero) } else { limit }));
+    return result;
+
+    forward_label!('negative);
+    result.push("negative");
+    return result;
+
+    forward_label!('large);
+    result.push("large");
+    return result;
+
+    forward_label!('zero);
+    result.push("zero");
+    result
+}
+
+fn check_limit(limit: i32) -> &'static str {
+    if limit > 10 { "high" } else { "low" }
+}
+
+#[rewrite_forward_goto(cleanup = 'cleanup)]
+fn test_nested_cleanup_method(values: &[&str], log: &mut Vec<String>) -> Result<u32, TryError> {
+    let mut i = 0;
+    while i < values.len() {
+        if values[i].is_empty() {
+            return Ok(0);
+        }
+        i += 1;
+    }
+    for value in values {
+        if *value == "-" {
+            return Err(TryError::Zero);
+        }
+    }
+    log.push(format_first(non_zero(values[0].parse()?)?));
+    return Ok(values.len() as u32);
+
+    forward_label!('cleanup);
+    log.push("cleanup".to_owned());
+}
+
+fn format_first(first: u32) -> String {
+    format!("first {}", first)
+}
+
+#[test]
+fn test_nested_goto() {
+    assert_eq!(test_nested_goto_method(&[1, 2], 5), ["begin", "low"]);
+    assert_eq!(test_nested_goto_method(&[1, 2], 20), ["begin", "high"]);
+    assert_eq!(test_nested_goto_method(&[1, -2], 5), ["begin", "negative"]);
+    assert_eq!(test_nested_goto_method(&[1, 7], 5), ["begin", "large"]);
+    assert_eq!(test_nested_goto_method(&[], 0), ["begin", "zero"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_nested_cleanup_method(&["3", "4"], &mut log), Ok(2));
+    assert_eq!(log, ["first 3", "cleanup"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_nested_cleanup_method(&["3", ""], &mut log), Ok(0));
+    assert_eq!(log, ["cleanup"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_nested_cleanup_method(&["3", "-"], &mut log), Err(TryError::Zero));
+    assert_eq!(log, ["cleanup"]);
+
+    let mut log = Vec::new();
+    assert_eq!(test_nested_cleanup_method(&["0"], &mut log), Err(TryError::Zero));
+    assert_eq!(log, ["cleanup"]);
+
+    let mut log = Vec::new();
+    assert!(matches!(test_nested_cleanup_method(&["x"], &mut log), Err(TryError::Parse(_))));
+    assert_eq!(log, ["cleanup"]);
+}
+
+
+macro_rules! fields_method {
+    ($name:ident, $($field:literal),*) => {
+        #[rewrite_forward_goto]
+        fn $name(skip: &[&str]) -> Vec<&'static str> {
+            let mut result = vec!["begin"];
+            $(
+                if skip.contains(&$field) {
+                    forward_goto!('next);
+                }
+                result.push($field);
+                forward_label!('next);
+            )*
+            result.push("end");
+            result
+        }
+    };
+}
+
+fields_method!(test_reused_label_fields_method, "a", "b", "c");
+
+#[rewrite_forward_goto]
+fn test_reused_label_method(a: bool, b: bool) -> Vec<&'static str> {
+    let mut result = vec!["begin"];
+
+    if a {
+        forward_goto!('next);
+    }
+    result.push("first");
+    forward_label!('next);
+
+    if b {
+        forward_goto!('next);
+    }
+    result.push("second");
+    {
+        result.push("block");
+        // the goto above binds to this label, because it is the nearest one
+        forward_label!('next);
+        if a {
+            forward_goto!('next);
+        }
+        result.push("third");
+        forward_label!('next);
+    }
+
+    result.push("end");
+    result
+}
+
+#[test]
+fn test_reused_label() {
+    assert_eq!(test_reused_label_fields_method(&[]), vec!["begin", "a", "b", "c", "end"]);
+    assert_eq!(test_reused_label_fields_method(&["a", "c"]), vec!["begin", "b", "end"]);
+    assert_eq!(test_reused_label_fields_method(&["b"]), vec!["begin", "a", "c", "end"]);
+
+    assert_eq!(test_reused_label_method(false, false),
+        vec![
+            "begin",
+            "first",
+            "second",
+            "block",
+            "third",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_reused_label_method(true, true),
+        vec![
+            "begin",
+            "end",
+        ]
+    );
+
+    assert_eq!(test_reused_label_method(false, true),
+        vec![
+            "begin",
+            "first",
+            "third",
+            "end",
+        ]
+    );
+}
+
+
+macro_rules! check {
+    ($cond:expr, $label:lifetime) => {
+        if !$cond {
+            forward_goto!($label);
+        }
+    };
+}
+
+#[rewrite_forward_goto]
+#[allow(clippy::needless_lifetimes)]
+fn test_macro_goto_method<'a>(word: &'a str) -> Vec<&'a str> {
+    let mut result = vec!["begin"];
+
+    check!(!word.is_empty(), 'a);
+    result.push(word);
+    check! { word.len() < 3, 'a }
+    result.push("short");
+
+    // the label is renamed, because its name is taken by the lifetime
+    forward_label!('a);
+
+    check!(word.starts_with('x'), 'next);
+    result.push("x");
+    forward_label!('next);
+
+    check!(word.ends_with('x'), 'next);
+    result.push("x");
+    forward_label!('next);
+
+    result.push("end");
+    result
+}
+
+#[test]
+fn test_macro_goto() {
+    assert_eq!(test_macro_goto_method(""), vec!["begin", "end"]);
+    assert_eq!(test_macro_goto_method("ab"), vec!["begin", "ab", "short", "end"]);
+    assert_eq!(test_macro_goto_method("xyz"), vec!["begin", "xyz", "x", "end"]);
+    assert_eq!(test_macro_goto_method("ax"), vec!["begin", "ax", "short", "x", "end"]);
+}
+
+#[rewrite_forward_goto]
+#[allow(clippy::needless_lifetimes)]
+fn test_macro_lifetime_method<'a>(word: &'a str, skip: bool) -> usize {
+    if skip {
+        forward_goto!('a);
+    }
+    // not a goto, the lifetime is part of a type
+    assert!(std::mem::size_of::<&'a str>() == 2 * std::mem::size_of::<usize>());
+    forward_label!('a);
+    word.len()
+}
+
+#[test]
+fn test_macro_lifetime() {
+    assert_eq!(test_macro_lifetime_method("ab", false), 2);
+    assert_eq!(test_macro_lifetime_method("ab", true), 2);
+}
+
+#[rewrite_forward_goto]
+fn test_cfg_method(trace: &mut Vec<i32>, b: bool) -> Result<(), TryError> {
+    #[cfg(not(test))]
+    forward_goto!('skip);
+    #[cfg(not(test))]
+    forward_goto_if!(!b, 'skip);
+    #[cfg(test)]
+    forward_goto_if!(b, 'skip);
+    trace.push(1);
+    forward_label!('skip);
+
+    #[cfg(not(test))]
+    forward_try!("x".parse::<u32>(), 'fail);
+    #[cfg(test)]
+    forward_try!("1".parse::<u32>(), 'fail);
+    trace.push(2);
+    return Ok(());
+
+    forward_label!('fail, err: TryError);
+    trace.push(3);
+    Err(err)
+}
+
+#[test]
+fn test_cfg() {
+    let mut trace = Vec::new();
+    assert_eq!(test_cfg_method(&mut trace, false), Ok(()));
+    assert_eq!(trace, [1, 2]);
+
+    let mut trace = Vec::new();
+    assert_eq!(test_cfg_method(&mut trace, true), Ok(()));
+    assert_eq!(trace, [2]);
+}
+
+macro_rules! wrapped_method {
+    ($(#[$attr:meta])* fn $name:ident) => {
+        $(#[$attr])*
+        fn $name(trace: &mut Vec<i32>, a: bool, b: bool) -> i32 {
+            if a {
+                forward_goto!('a);
+            }
+            trace.push(1);
+            if b {
+                return 1;
+            }
+
+            forward_label!('a);
+            trace.push(2);
+            2
+        }
+    };
+}
+
+wrapped_method!(#[rewrite_forward_goto] #[wrap_async] fn test_wrapped_async_inner_method);
+wrapped_method!(#[wrap_async] #[rewrite_forward_goto] fn test_wrapped_async_outer_method);
+wrapped_method!(#[rewrite_forward_goto] #[wrap_closure] fn test_wrapped_closure_inner_method);
+wrapped_method!(#[wrap_closure] #[rewrite_forward_goto] fn test_wrapped_closure_outer_method);
+
+#[wrap_closure]
+#[wrap_async]
+#[rewrite_forward_goto(cleanup = 'done)]
+fn test_wrapped_cleanup_method(trace: &mut Vec<i32>, b: bool) -> i32 {
+    trace.push(1);
+    if b {
+        return 1;
+    }
+    return 2;
+
+    forward_label!('done);
+    trace.push(2);
+}
+
+type WrappedMethod = fn(&mut Vec<i32>, bool, bool) -> i32;
+
+#[test]
+fn test_wrapped() {
+    let methods: [WrappedMethod; 4] = [
+        test_wrapped_async_inner_method,
+        test_wrapped_async_outer_method,
+        test_wrapped_closure_inner_method,
+        test_wrapped_closure_outer_method,
+    ];
+
+    for method in methods {
+        let mut trace = Vec::new();
+        assert_eq!(method(&mut trace, true, true), 2);
+        assert_eq!(trace, [2]);
+
+        let mut trace = Vec::new();
+        assert_eq!(method(&mut trace, false, true), 1);
+        assert_eq!(trace, [1]);
+
+        let mut trace = Vec::new();
+        assert_eq!(method(&mut trace, false, false), 2);
+        assert_eq!(trace, [1, 2]);
+    }
+
+    // the return in front of the cleanup label leaves the closure and the async block through it
+    let mut trace = Vec::new();
+    assert_eq!(test_wrapped_cleanup_method(&mut trace, true), 1);
+    assert_eq!(trace, [1, 2]);
+
+    let mut trace = Vec::new();
+    assert_eq!(test_wrapped_cleanup_method(&mut trace, false), 2);
+    assert_eq!(trace, [1, 2]);
+}
+
+#[rewrite_forward_goto(trace)]
+fn test_trace_method(x: u32) -> Result<u32, TryError> {
+    forward_goto_if!(x == 0, 'zero);
+    let y = forward_try!(non_zero(x - 1), 'fail);
+    return Ok(y);
+
+    forward_label!('fail, err: TryError);
+    return Err(err);
+
+    forward_label!('zero);
+    Ok(0)
+}
+
+#[test]
+fn test_trace() {
+    use forward_goto::trace::{self, Event};
+
+    let path = |x| {
+        let (result, hits) = trace::record(|| test_trace_method(x));
+        assert!(hits.iter().all(|hit| hit.function == "test_trace_method"));
+        let path: Vec<_> = hits.iter().map(|hit| (hit.event, hit.label)).collect();
+        (result, path)
+    };
+
+    assert_eq!(path(0), (Ok(0), vec![(Event::Goto, "'zero"), (Event::Label, "'zero")]));
+    assert_eq!(path(1), (Err(TryError::Zero), vec![(Event::Goto, "'fail"), (Event::Label, "'fail")]));
+    assert_eq!(path(2), (Ok(1), vec![]));
+
+    let (_, hits) = trace::record(|| test_trace_method(0));
+    assert_eq!(hits[1].line, hits[0].line + 7);
+    assert_eq!(hits[0].to_string(), format!("test_trace_method: goto 'zero (line {})", hits[0].line));
+
+    let (result, hits) = trace::record(|| test_trace_crate_method(true));
+    assert_eq!(result, 0);
+    assert_eq!(hits.iter().map(|hit| hit.event).collect::<Vec<_>>(), [Event::Goto, Event::Label]);
+}
+
+mod reexport {
+    pub use forward_goto as goto;
+}
+
+#[rewrite_forward_goto(trace, crate = "crate::reexport::goto")]
+fn test_trace_crate_method(b: bool) -> i32 {
+    forward_goto_if!(b, 'skip);
+    return 1;
+
+    forward_label!('skip);
+    0
+}